@@ -1,26 +1,121 @@
 use serde_derive::*;
 
+use crate::tensor::Tensor;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ActivationFunction {
     Sigmoid,
     ReLU,
+    LeakyReLU(f64), //slope for negative inputs
+    PReLU, //slope learned per neuron in dense layers, per channel in conv layers
     TanH,
-    SoftMax
+    SoftMax,
+    LogSoftMax,
+    GELU, //tanh approximation
+    SiLU, //a.k.a. Swish, x * sigmoid(x)
+    ELU, //alpha = 1
+    SELU,
+    Softplus,
+    Mish,
+    HardSigmoid, //clamp(x / 6 + 1 / 2, 0, 1)
+    Identity, //linear output, e.g. for regression
+}
+
+const SELU_ALPHA: f64 = 1.6732632423543772;
+const SELU_SCALE: f64 = 1.0507009873554805;
+const GELU_COEFF: f64 = 0.044715;
+const PRELU_SLOPE: f64 = 0.25; //starting slope
+const VERSION: u32 = 1;
+
+fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+// ln(1 + e^x) without overflowing for large x
+fn softplus(x: f64) -> f64 {
+    x.max(0.0) + (-x.abs()).exp().ln_1p()
+}
+
+// ln(sum(exp(x))) without overflowing for large x
+pub fn log_sum_exp(inputs: &[f64]) -> f64 {
+    let max = inputs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if max.is_infinite() {
+        return max;
+    }
+    max + inputs.iter().map(|x| (x - max).exp()).sum::<f64>().ln()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "ActivationState", into = "ActivationState")]
 pub struct Activation {
     pub function: ActivationFunction,
+    pub slopes: Tensor, //PReLU only
+    pub grad_slopes: Tensor,
+}
+
+// What gets saved, models from before version 1 used "ReLU" for a leaky ReLU with slope 0.01
+#[derive(Serialize, Deserialize)]
+struct ActivationState {
+    function: ActivationFunction,
+    #[serde(default)]
+    slopes: Tensor,
+    #[serde(default)]
+    grad_slopes: Tensor,
+    #[serde(default)]
+    version: u32,
+}
+
+impl From<ActivationState> for Activation {
+    fn from(state: ActivationState) -> Self {
+        let function = match state.function {
+            ActivationFunction::ReLU if state.version == 0 => ActivationFunction::LeakyReLU(0.01),
+            function => function,
+        };
+        Activation {
+            function,
+            slopes: state.slopes,
+            grad_slopes: state.grad_slopes,
+        }
+    }
+}
+
+impl From<Activation> for ActivationState {
+    fn from(activation: Activation) -> Self {
+        ActivationState {
+            function: activation.function,
+            slopes: activation.slopes,
+            grad_slopes: activation.grad_slopes,
+            version: VERSION,
+        }
+    }
 }
 
 impl Activation {
     pub fn new(function: ActivationFunction) -> Self {
         Activation {
-            function
+            function,
+            slopes: Tensor::default(),
+            grad_slopes: Tensor::default(),
+        }
+    }
+
+    // Gives PReLU one slope per unit (neuron or channel), other activations have no parameters
+    pub fn init(&mut self, units: usize) {
+        if self.function == ActivationFunction::PReLU {
+            self.slopes = Tensor::filled(&[units], PRELU_SLOPE);
+            self.grad_slopes = Tensor::zeros(&[units]);
+        }
+    }
+
+    // Slope for element i of a whole sample, every unit covers an equal share of the sample
+    fn slope(&self, i: usize, len: usize) -> f64 {
+        if self.slopes.is_empty() {
+            return PRELU_SLOPE;
         }
+        self.slopes.data()[i * self.slopes.len() / len]
     }
-    pub fn function(&self, inp: Vec<f64>) -> Vec<f64> {
-        let inputs = inp.clone();
+
+    pub fn function(&self, inputs: &[f64]) -> Vec<f64> {
         match self.function {
             ActivationFunction::Sigmoid => 
                 {
@@ -32,16 +127,15 @@ impl Activation {
                 },
             ActivationFunction::ReLU => 
                 {
-                    let mut outputs = vec![0.0; inputs.len()];
-                    for i in 0..outputs.len() {
-                        let x = inputs[i];
-                        outputs[i] = if x > 0.0 {
-                            x
-                        } else {
-                            x * 0.01
-                        };
-                    }
-                    outputs  
+                    inputs.iter().map(|x| x.max(0.0)).collect()
+                },
+            ActivationFunction::LeakyReLU(alpha) => 
+                {
+                    inputs.iter().map(|x| if *x > 0.0 { *x } else { x * alpha }).collect()
+                },
+            ActivationFunction::PReLU => 
+                {
+                    inputs.iter().enumerate().map(|(i, x)| if *x > 0.0 { *x } else { x * self.slope(i, inputs.len()) }).collect()
                 },
             ActivationFunction::TanH =>
                 {
@@ -54,21 +148,59 @@ impl Activation {
                 },
             ActivationFunction::SoftMax => 
                 {
-                    let mean = inputs.clone().iter().sum::<f64>() / inputs.len() as f64;
-                    // let mean = 0.0;
-                    let sum_exp: f64 = inputs.clone().iter().map(|x| (x - mean).exp()).sum();
+                    // Shifting by the max keeps every exponent <= 0, so nothing overflows
+                    let max = inputs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                    let sum_exp: f64 = inputs.iter().map(|x| (x - max).exp()).sum();
 
                     let mut outputs = vec![0.0; inputs.len()];
                     for i in 0..outputs.len() {
-                        outputs[i] = ((inputs[i] - mean).exp()) / sum_exp;
+                        outputs[i] = ((inputs[i] - max).exp()) / sum_exp;
                     }
                     outputs 
                 },
+            ActivationFunction::LogSoftMax => 
+                {
+                    let log_sum_exp = log_sum_exp(inputs);
+                    inputs.iter().map(|x| x - log_sum_exp).collect()
+                },
+            ActivationFunction::GELU => 
+                {
+                    let c = (2.0 / std::f64::consts::PI).sqrt();
+                    inputs.iter().map(|x| 0.5 * x * (1.0 + (c * (x + GELU_COEFF * x.powi(3))).tanh())).collect()
+                },
+            ActivationFunction::SiLU => 
+                {
+                    inputs.iter().map(|x| x * sigmoid(*x)).collect()
+                },
+            ActivationFunction::ELU => 
+                {
+                    inputs.iter().map(|x| if *x > 0.0 { *x } else { x.exp_m1() }).collect()
+                },
+            ActivationFunction::SELU => 
+                {
+                    inputs.iter().map(|x| SELU_SCALE * if *x > 0.0 { *x } else { SELU_ALPHA * x.exp_m1() }).collect()
+                },
+            ActivationFunction::Softplus => 
+                {
+                    inputs.iter().map(|x| softplus(*x)).collect()
+                },
+            ActivationFunction::Mish => 
+                {
+                    inputs.iter().map(|x| x * softplus(*x).tanh()).collect()
+                },
+            ActivationFunction::HardSigmoid => 
+                {
+                    inputs.iter().map(|x| (x / 6.0 + 0.5).clamp(0.0, 1.0)).collect()
+                },
+            ActivationFunction::Identity => 
+                {
+                    inputs.to_vec()
+                },
         }
     }
 
-    pub fn derivative(&self, outs: Vec<f64>) -> Vec<f64> {
-        let outputs = outs.clone();
+    // Elementwise derivative, some activations need the weighted inputs and others their outputs
+    pub fn derivative(&self, weighted_inputs: &[f64], outputs: &[f64]) -> Vec<f64> {
         match self.function {
             ActivationFunction::Sigmoid => 
                 {
@@ -81,16 +213,15 @@ impl Activation {
                 },
             ActivationFunction::ReLU => 
                 {
-                    let mut gradients = vec![0.0; outputs.len()];
-                    for i in 0..outputs.len() {
-                        let x = outputs[i];
-                        gradients[i] = if x < 0.0 {
-                            0.01
-                        } else {
-                            1.0
-                        };
-                    }
-                    gradients
+                    weighted_inputs.iter().map(|x| if *x > 0.0 { 1.0 } else { 0.0 }).collect()
+                },
+            ActivationFunction::LeakyReLU(alpha) => 
+                {
+                    weighted_inputs.iter().map(|x| if *x > 0.0 { 1.0 } else { alpha }).collect()
+                },
+            ActivationFunction::PReLU => 
+                {
+                    weighted_inputs.iter().enumerate().map(|(i, x)| if *x > 0.0 { 1.0 } else { self.slope(i, weighted_inputs.len()) }).collect()
                 },
             ActivationFunction::TanH => 
             {
@@ -103,8 +234,129 @@ impl Activation {
             },
             ActivationFunction::SoftMax => 
                 {
-                    vec![]              
+                    // Diagonal of the Jacobian only, backward applies the full Jacobian
+                    outputs.iter().map(|s| s * (1.0 - s)).collect()
+                },
+            ActivationFunction::LogSoftMax => 
+                {
+                    outputs.iter().map(|y| 1.0 - y.exp()).collect()
+                },
+            ActivationFunction::GELU => 
+                {
+                    let c = (2.0 / std::f64::consts::PI).sqrt();
+                    weighted_inputs.iter().map(|x| {
+                        let t = (c * (x + GELU_COEFF * x.powi(3))).tanh();
+                        0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * c * (1.0 + 3.0 * GELU_COEFF * x * x)
+                    }).collect()
+                },
+            ActivationFunction::SiLU => 
+                {
+                    weighted_inputs.iter().map(|x| {
+                        let s = sigmoid(*x);
+                        s + x * s * (1.0 - s)
+                    }).collect()
+                },
+            ActivationFunction::ELU => 
+                {
+                    weighted_inputs.iter().map(|x| if *x > 0.0 { 1.0 } else { x.exp() }).collect()
+                },
+            ActivationFunction::SELU => 
+                {
+                    weighted_inputs.iter().map(|x| SELU_SCALE * if *x > 0.0 { 1.0 } else { SELU_ALPHA * x.exp() }).collect()
+                },
+            ActivationFunction::Softplus => 
+                {
+                    weighted_inputs.iter().map(|x| sigmoid(*x)).collect()
+                },
+            ActivationFunction::Mish => 
+                {
+                    weighted_inputs.iter().map(|x| {
+                        let t = softplus(*x).tanh();
+                        t + x * sigmoid(*x) * (1.0 - t * t)
+                    }).collect()
+                },
+            ActivationFunction::HardSigmoid => 
+                {
+                    weighted_inputs.iter().map(|x| if x.abs() < 3.0 { 1.0 / 6.0 } else { 0.0 }).collect()
+                },
+            ActivationFunction::Identity => 
+                {
+                    vec![1.0; weighted_inputs.len()]
+                },
+        }
+    }
+
+    // Vector-Jacobian product, turns the error on the outputs into the error on the weighted inputs
+    pub fn backward(&self, weighted_inputs: &[f64], outputs: &[f64], errors: &[f64]) -> Vec<f64> {
+        match self.function {
+            ActivationFunction::SoftMax => 
+                {
+                    let dot: f64 = outputs.iter().zip(errors).map(|(s, e)| s * e).sum();
+                    outputs.iter().zip(errors).map(|(s, e)| s * (e - dot)).collect()
+                },
+            ActivationFunction::LogSoftMax => 
+                {
+                    let sum: f64 = errors.iter().sum();
+                    outputs.iter().zip(errors).map(|(y, e)| e - y.exp() * sum).collect()
                 },
+            _ => self.derivative(weighted_inputs, outputs).iter().zip(errors).map(|(gradient, e)| gradient * e).collect(),
+        }
+    }
+
+    // Accumulates the PReLU slope gradients for one whole sample
+    pub fn backward_slopes(&mut self, weighted_inputs: &[f64], errors: &[f64]) {
+        if self.slopes.is_empty() {
+            return;
+        }
+        let units = self.slopes.len();
+        let grads = self.grad_slopes.data_mut();
+        for (i, (x, e)) in weighted_inputs.iter().zip(errors).enumerate() {
+            if *x <= 0.0 {
+                grads[i * units / weighted_inputs.len()] += x * e;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUTS: [f64; 4] = [0.3, -1.2, 2.0, 0.1];
+    const ERRORS: [f64; 4] = [0.5, -0.25, 1.0, -0.75];
+
+    // backward against central differences of sum(errors * function(inputs))
+    fn check_backward(function: ActivationFunction, inputs: &[f64]) {
+        let activation = Activation::new(function);
+        let weighted = |inputs: &[f64]| activation.function(inputs).iter().zip(ERRORS).map(|(y, e)| y * e).sum::<f64>();
+        let outputs = activation.function(inputs);
+        let analytic = activation.backward(inputs, &outputs, &ERRORS);
+        for i in 0..inputs.len() {
+            let eps = 1e-6;
+            let mut plus = inputs.to_vec();
+            let mut minus = inputs.to_vec();
+            plus[i] += eps;
+            minus[i] -= eps;
+            let numeric = (weighted(&plus) - weighted(&minus)) / (2.0 * eps);
+            assert!((analytic[i] - numeric).abs() < 1e-6, "{:?} input {}: {} vs {}", activation.function, i, analytic[i], numeric);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn softmax_backward_is_the_jacobian_product() {
+        check_backward(ActivationFunction::SoftMax, &INPUTS);
+    }
+    #[test]
+    fn log_softmax_backward_is_the_jacobian_product() {
+        check_backward(ActivationFunction::LogSoftMax, &INPUTS);
+    }
+
+    #[test]
+    fn softmax_stays_finite_for_large_inputs() {
+        let inputs = [1000.0, 999.0, -1000.0];
+        let probabilities = Activation::new(ActivationFunction::SoftMax).function(&inputs);
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(Activation::new(ActivationFunction::LogSoftMax).function(&inputs).iter().all(|y| y.is_finite()));
+        check_backward(ActivationFunction::SoftMax, &[50.0, 49.0, 48.5, 47.0]);
+    }
+}
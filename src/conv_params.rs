@@ -1,7 +1,7 @@
 use serde_derive::{Serialize, Deserialize};
 use rand::prelude::*;
 
-use crate::activation::{ActivationFunction};
+use crate::{activation::{Activation, ActivationFunction}, initializer::{self, Initializer}, tensor::Tensor};
 
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -14,139 +14,173 @@ pub enum PaddingType {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConvParams {
     pub kernel: usize,
+    pub out_channels: usize,
     pub padding_type: PaddingType,
     pub padding: usize,
     pub stride: usize,
-    pub data: Vec<Vec<Vec<f64>>>, //channel > img matrix
-    pub weights: Vec<Vec<Vec<f64>>>, //1 kernel > depth of kernel -> kernel weights
-    pub bias: f64, //bias for each kernel not including depth
-    pub outputs: Vec<Vec<Vec<f64>>>, //channel > mat
-    pub inputs: Vec<Vec<Vec<f64>>>, //channel > mat
+    pub data: Tensor, //sample > channel > img matrix
+    pub weights: Tensor, //filter > input channel > kernel rows > kernel cols
+    pub biases: Tensor, //one bias per filter
+    pub grad_weights: Tensor, //accumulated by backward, cleared by step
+    pub grad_biases: Tensor,
+    pub outputs: Tensor, //sample > filter > mat
+    pub weighted_inputs: Tensor, //outputs before the activation
+    pub inputs: Tensor, //sample > channel > mat
+    #[serde(default)]
+    pub initializer: Initializer,
+    #[serde(default)]
+    pub seed: Option<u64>, //None draws fresh weights on every init
 }
 
 impl ConvParams {
-    pub fn new(kernel: usize, padding_type: PaddingType, stride: usize) -> Self {
+    pub fn new(kernel: usize, out_channels: usize, padding_type: PaddingType, stride: usize) -> Self {
         
-        let mut cp = ConvParams {
+        ConvParams {
             kernel,
+            out_channels,
             padding_type,
             padding: 0,
             stride,
-            data: vec![],
-            weights: vec![],
-            bias: 0.0,
-            outputs: vec![],
-            inputs: vec![],
-        };
-        cp
+            data: Tensor::default(),
+            weights: Tensor::default(),
+            biases: Tensor::default(),
+            grad_weights: Tensor::default(),
+            grad_biases: Tensor::default(),
+            outputs: Tensor::default(),
+            weighted_inputs: Tensor::default(),
+            inputs: Tensor::default(),
+            initializer: Initializer::default(),
+            seed: None,
+        }
     }
 
-    pub fn init(&mut self, activation: ActivationFunction) {
-        if self.weights.len() != 0 as usize {
+    pub fn init(&mut self, activation: ActivationFunction, in_channels: usize) {
+        if !self.weights.is_empty() {
             return;
         }
-        for i in 0..self.data.len() {
-            self.weights.push(vec![vec![0.0; self.kernel]; self.kernel]);
+        self.weights = Tensor::zeros(&[self.out_channels, in_channels, self.kernel, self.kernel]);
+        self.biases = Tensor::zeros(&[self.out_channels]);
+        self.grad_weights = Tensor::zeros(self.weights.shape());
+        self.grad_biases = Tensor::zeros(self.biases.shape());
+        let fan_in = in_channels * self.kernel * self.kernel;
+        let mut rng = initializer::seeded_rng(self.seed);
+        if self.initializer != Initializer::Auto {
+            self.initializer.init(&mut self.weights, fan_in, self.out_channels * self.kernel * self.kernel, &mut rng);
+            return;
         }
 
         match activation {
-            ActivationFunction::Sigmoid => 
+            ActivationFunction::Sigmoid | ActivationFunction::Softplus | ActivationFunction::HardSigmoid => 
                 {
-                    let std_dev = (2.0 / (self.weights.len() * (self.weights[0].len() * self.weights[0].len())) as f64).sqrt();
+                    let std_dev = (2.0 / fan_in as f64).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    self.bias = 0.0;
-
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            for k in 0..self.weights[i][j].len() {
-                                self.weights[i][j][k] = thread_rng().gen_range(-limit..limit) * std_dev;
-                            }
-                        }
+
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
                 },
-            ActivationFunction::ReLU => 
+            ActivationFunction::ReLU | ActivationFunction::LeakyReLU(_) | ActivationFunction::PReLU | ActivationFunction::GELU | ActivationFunction::SiLU | ActivationFunction::ELU | ActivationFunction::Mish => 
                 {
-                    let std_dev = (2.0 / (self.weights.len() * (self.weights[0].len() * self.weights[0].len())) as f64).sqrt();
-                    let limit = (3.0 * std_dev).sqrt();
-                    self.bias = 0.0;
-
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            for k in 0..self.weights[i][j].len() {
-                                self.weights[i][j][k] = thread_rng().gen_range(-std_dev..std_dev);
-                            }
-                        }
+                    let std_dev = (2.0 / fan_in as f64).sqrt();
+
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-std_dev..std_dev);
                     }
                 },
-            ActivationFunction::TanH => 
+            ActivationFunction::TanH | ActivationFunction::SELU | ActivationFunction::Identity => 
                 {
-                    let std_dev = (1.0 / (self.weights.len() * (self.weights[0].len() * self.weights[0].len())) as f64).sqrt();
+                    let std_dev = (1.0 / fan_in as f64).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    self.bias = 0.0;
-
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            for k in 0..self.weights[i][j].len() {
-                                self.weights[i][j][k] = thread_rng().gen_range(-limit..limit) * std_dev;
-                            }
-                        }
+
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
                 },
-            ActivationFunction::SoftMax => 
+            ActivationFunction::SoftMax | ActivationFunction::LogSoftMax => 
                 {
-                    let std_dev = (1.0 / (self.weights.len() * (self.weights[0].len() * self.weights[0].len())) as f64).sqrt();
+                    let std_dev = (1.0 / fan_in as f64).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    self.bias = 0.0;
-
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            for k in 0..self.weights[i][j].len() {
-                                self.weights[i][j][k] = thread_rng().gen_range(-limit..limit) * std_dev;
-                            }
-                        }
+
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
                 },
         }
     }
 
-    pub fn add_padding(&mut self, activation: ActivationFunction) {
+    // Weights and biases, plus the activation's slopes when it learns them (PReLU). Weights and biases
+    // stay empty until the first forward pass knows the input channels
+    pub fn parameters<'a>(&'a mut self, activation: &'a mut Activation) -> Vec<(&'a mut Tensor, &'a mut Tensor)> {
+        let mut parameters = vec![(&mut self.weights, &mut self.grad_weights), (&mut self.biases, &mut self.grad_biases)];
+        if !activation.slopes.is_empty() {
+            parameters.push((&mut activation.slopes, &mut activation.grad_slopes));
+        }
+        parameters
+    }
+
+    // Clears the cached passes and gradients, weights that are still empty get created on the next forward pass
+    pub fn reset(&mut self, activation: &mut Activation) {
+        self.inputs = Tensor::default();
+        self.weighted_inputs = Tensor::default();
+        self.outputs = Tensor::default();
+        self.grad_weights.fill(0.0);
+        self.grad_biases.fill(0.0);
+        activation.init(self.out_channels);
+    }
+
+    // Weights are drawn again from the seed on the next forward pass
+    pub fn clear_weights(&mut self) {
+        self.weights = Tensor::default();
+    }
+
+    pub fn add_padding(&mut self) {
         if self.padding_type == PaddingType::Valid {
             self.data = self.inputs.clone();
-            self.init(activation);
             return;
         }
-        let mut padded_image = vec![];
-        let mut padding = (self.kernel - 1) / 2;
-
-        if self.padding_type == PaddingType::Full {
-            padding = self.kernel - 1;
-        }
-
-        self.padding = padding;
-        let height = self.inputs[0].len();
-        let width = self.inputs[0][0].len();
+        self.padding = self.padding_size();
+        let batch = self.inputs.shape()[0];
+        let channels = self.inputs.shape()[1];
+        let height = self.inputs.shape()[2];
+        let width = self.inputs.shape()[3];
         let padded_height = height + 2 * self.padding;
         let padded_width = width + 2 * self.padding;
-        
-        for i in 0..self.inputs.len() {
-            padded_image.push(vec![vec![0.0; padded_width]; padded_height]);
-        }
 
-        for i in 0..padded_image.len() {
-            for j in 0..height {
-                for k in 0..width {
-                    padded_image[i][j + self.padding][k + self.padding] = self.inputs[i][j][k];
+        let mut padded_image = Tensor::zeros(&[batch, channels, padded_height, padded_width]);
+
+        for n in 0..batch {
+            for i in 0..channels {
+                for j in 0..height {
+                    for k in 0..width {
+                        padded_image[[n, i, j + self.padding, k + self.padding]] = self.inputs[[n, i, j, k]];
+                    }
                 }
             }
         }
 
         self.data = padded_image;
-        self.init(activation);
+    }
+
+    pub fn padding_size(&self) -> usize {
+        match self.padding_type {
+            PaddingType::Valid => 0,
+            PaddingType::Same => (self.kernel - 1) / 2,
+            PaddingType::Full => self.kernel - 1,
+        }
+    }
+
+    // [out_height, out_width] for an unpadded input, usable before any forward pass
+    pub fn get_output_size(&self, height: usize, width: usize) -> [usize; 2] {
+        let padding = self.padding_size();
+        [
+            (height + 2 * padding - self.kernel) / self.stride + 1,
+            (width + 2 * padding - self.kernel) / self.stride + 1,
+        ]
     }
 
     pub fn get_output_dims(&self) -> [usize; 2] {
-        let height = self.data[0].len();
-        let width = self.data[0][0].len();
+        let height = self.data.shape()[2];
+        let width = self.data.shape()[3];
 
         let out_width = (width - self.kernel) / self.stride + 1;
         let out_height = (height - self.kernel) / self.stride + 1;
@@ -157,6 +191,6 @@ impl ConvParams {
     pub fn print_kernels(&self) {
         println!("--------------------------\nKernel Dimensions: {} x {}", self.kernel, self.kernel);
         println!("Weights: \n{:#?}", self.weights);
-        println!("Biases: \n{:#?}\n-------------------------------", self.bias);
+        println!("Biases: \n{:#?}\n-------------------------------", self.biases);
     }
 }
\ No newline at end of file
@@ -0,0 +1,43 @@
+use crate::tensor::Tensor;
+
+// (input, target) pairs, both the dense `[inputs, targets]` layout and the conv `(image, targets)` layout convert into it
+#[derive(Debug, Clone, Default)]
+pub struct Dataset {
+    pub samples: Vec<(Tensor, Tensor)>,
+}
+
+impl Dataset {
+    pub fn new() -> Self {
+        Dataset {
+            samples: vec![],
+        }
+    }
+
+    pub fn push(&mut self, input: impl Into<Tensor>, target: impl Into<Tensor>) {
+        self.samples.push((input.into(), target.into()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+impl<I: Into<Tensor>, T: Into<Tensor>> From<Vec<(I, T)>> for Dataset {
+    fn from(data: Vec<(I, T)>) -> Self {
+        Dataset {
+            samples: data.into_iter().map(|(input, target)| (input.into(), target.into())).collect(),
+        }
+    }
+}
+
+impl<D: Into<Tensor>> From<Vec<[D; 2]>> for Dataset {
+    fn from(data: Vec<[D; 2]>) -> Self {
+        Dataset {
+            samples: data.into_iter().map(|[input, target]| (input.into(), target.into())).collect(),
+        }
+    }
+}
@@ -1,16 +1,23 @@
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use serde_derive::{Serialize, Deserialize};
 
-use crate::activation::ActivationFunction;
+use crate::{activation::ActivationFunction, initializer::{self, Initializer}, tensor::Tensor};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DenseParams {
     pub nodes_in: usize,
     pub nodes_out: usize,
-    pub outputs: Vec<f64>, //nodes out
-    pub inputs: Vec<f64>,
-    pub weights: Vec<Vec<f64>>,
-    pub biases: Vec<f64>,
+    pub outputs: Tensor, //nodes out
+    pub weighted_inputs: Tensor, //outputs before the activation
+    pub inputs: Tensor,
+    pub weights: Tensor, //in (rows) - out (cols)
+    pub biases: Tensor,
+    pub grad_weights: Tensor, //accumulated by backward, cleared by step
+    pub grad_biases: Tensor,
+    #[serde(default)]
+    pub initializer: Initializer,
+    #[serde(default)]
+    pub seed: Option<u64>, //None draws fresh weights on every init
 }
 
 impl DenseParams {
@@ -18,63 +25,75 @@ impl DenseParams {
         nodes_in: usize,
         nodes_out: usize,
     ) -> Self {
-        let weights = vec![vec![0.0; nodes_out]; nodes_in];
-        let biases = vec![0.0; nodes_out];
-        let params = DenseParams {
+        let weights = Tensor::zeros(&[nodes_in, nodes_out]);
+        let biases = Tensor::zeros(&[nodes_out]);
+        DenseParams {
             nodes_in,
             nodes_out,
-            outputs: vec![],
-            inputs: vec![],
+            outputs: Tensor::default(),
+            weighted_inputs: Tensor::default(),
+            inputs: Tensor::default(),
+            grad_weights: Tensor::zeros(weights.shape()),
+            grad_biases: Tensor::zeros(biases.shape()),
             weights,
             biases,
-        };
-        params
+            initializer: Initializer::default(),
+            seed: None,
+        }
     }
     pub fn init(&mut self, activation: ActivationFunction) {
+        let mut rng = initializer::seeded_rng(self.seed);
+        if self.initializer != Initializer::Auto {
+            self.initializer.init(&mut self.weights, self.nodes_in, self.nodes_out, &mut rng);
+            self.biases.fill(0.0);
+            return;
+        }
         match activation {
-            ActivationFunction::Sigmoid => 
+            ActivationFunction::Sigmoid | ActivationFunction::Softplus | ActivationFunction::HardSigmoid => 
                 {
                     let std_dev = (1.0 / ((self.nodes_in + self.nodes_out) as f64 / 2.0)).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            self.weights[i][j] = thread_rng().gen_range(-limit..limit) * std_dev; //in (rows) - out (cols)
-                        }
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
-                    self.biases = vec![0.0; self.biases.len()];
+                    self.biases.fill(0.0);
                 },
-            ActivationFunction::ReLU => 
+            ActivationFunction::ReLU | ActivationFunction::LeakyReLU(_) | ActivationFunction::PReLU | ActivationFunction::GELU | ActivationFunction::SiLU | ActivationFunction::ELU | ActivationFunction::Mish => 
                 {
                     let std_dev = (2.0 / self.nodes_in as f64).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            self.weights[i][j] = thread_rng().gen_range(-limit..limit) * std_dev; //in (rows) - out (cols)
-                        }
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
+                    }
+                    self.biases.fill(0.0);
+                },
+            ActivationFunction::SELU | ActivationFunction::Identity => 
+                {
+                    // LeCun scaling keeps SELU self-normalising, and matches the conv init for linear layers
+                    let std_dev = (1.0 / self.nodes_in as f64).sqrt();
+                    let limit = (3.0 * std_dev).sqrt();
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
-                    self.biases = vec![0.0; self.biases.len()];
+                    self.biases.fill(0.0);
                 },
             ActivationFunction::TanH => 
                 {
                     let std_dev = (1.0 / ((self.nodes_in + self.nodes_out) as f64 / 2.0)).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            self.weights[i][j] = thread_rng().gen_range(-limit..limit) * std_dev; //in (rows) - out (cols)
-                        }
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
-                    self.biases = vec![0.0; self.biases.len()];
+                    self.biases.fill(0.0);
                 },
-            ActivationFunction::SoftMax => 
+            ActivationFunction::SoftMax | ActivationFunction::LogSoftMax => 
                 {
                     let std_dev = (1.0 / ((self.nodes_in + self.nodes_out) as f64 / 2.0)).sqrt();
                     let limit = (3.0 * std_dev).sqrt();
-                    for i in 0..self.weights.len() {
-                        for j in 0..self.weights[i].len() {
-                            self.weights[i][j] = thread_rng().gen_range(-limit..limit) * std_dev; //in (rows) - out (cols)
-                        }
+                    for weight in self.weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit) * std_dev;
                     }
-                    self.biases = vec![0.0; self.biases.len()];
+                    self.biases.fill(0.0);
                 },
         }
     }
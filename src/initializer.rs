@@ -0,0 +1,134 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::tensor::Tensor;
+
+// Weight initialization schemes, fans are the inputs and outputs feeding one weight (times the kernel area for conv layers)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub enum Initializer {
+    #[default]
+    Auto, //scheme picked from the layer's activation
+    Zeros,
+    Constant(f64),
+    Uniform { low: f64, high: f64 },
+    Normal { mean: f64, std_dev: f64 },
+    XavierUniform,
+    XavierNormal,
+    HeUniform,
+    HeNormal,
+    LeCun, //normal with variance 1 / fan_in
+    Orthogonal,
+}
+
+// Layers keep the seed the network handed them so lazy and repeated inits draw the same weights
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// Standard normal sample through the Box-Muller transform
+pub fn normal(rng: &mut impl Rng) -> f64 {
+    let u: f64 = 1.0 - rng.gen::<f64>(); //(0, 1], keeps ln finite
+    let v: f64 = rng.gen();
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
+
+impl Initializer {
+    // Auto is left to the layer, which knows its activation
+    pub fn init(&self, weights: &mut Tensor, fan_in: usize, fan_out: usize, rng: &mut impl Rng) {
+        let fan_in = fan_in as f64;
+        let fan_out = fan_out as f64;
+        match *self {
+            Initializer::Auto => {},
+            Initializer::Zeros => weights.fill(0.0),
+            Initializer::Constant(value) => weights.fill(value),
+            Initializer::Uniform { low, high } =>
+                {
+                    for weight in weights.data_mut() {
+                        *weight = rng.gen_range(low..high);
+                    }
+                },
+            Initializer::Normal { mean, std_dev } =>
+                {
+                    for weight in weights.data_mut() {
+                        *weight = mean + std_dev * normal(rng);
+                    }
+                },
+            Initializer::XavierUniform =>
+                {
+                    let limit = (6.0 / (fan_in + fan_out)).sqrt();
+                    for weight in weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit);
+                    }
+                },
+            Initializer::XavierNormal =>
+                {
+                    let std_dev = (2.0 / (fan_in + fan_out)).sqrt();
+                    for weight in weights.data_mut() {
+                        *weight = std_dev * normal(rng);
+                    }
+                },
+            Initializer::HeUniform =>
+                {
+                    let limit = (6.0 / fan_in).sqrt();
+                    for weight in weights.data_mut() {
+                        *weight = rng.gen_range(-limit..limit);
+                    }
+                },
+            Initializer::HeNormal =>
+                {
+                    let std_dev = (2.0 / fan_in).sqrt();
+                    for weight in weights.data_mut() {
+                        *weight = std_dev * normal(rng);
+                    }
+                },
+            Initializer::LeCun =>
+                {
+                    let std_dev = (1.0 / fan_in).sqrt();
+                    for weight in weights.data_mut() {
+                        *weight = std_dev * normal(rng);
+                    }
+                },
+            Initializer::Orthogonal => orthogonal(weights, rng),
+        }
+    }
+}
+
+// Reads the weights as a matrix of shape[0] rows and orthonormalises whichever of rows or columns are fewer
+fn orthogonal(weights: &mut Tensor, rng: &mut impl Rng) {
+    if weights.is_empty() {
+        return;
+    }
+    let rows = weights.shape()[0];
+    let cols = weights.len() / rows;
+    let (count, len) = (rows.min(cols), rows.max(cols));
+
+    // Gram-Schmidt over random normal vectors
+    let mut vectors: Vec<Vec<f64>> = Vec::with_capacity(count);
+    while vectors.len() < count {
+        let mut vector: Vec<f64> = (0..len).map(|_| normal(rng)).collect();
+        for basis in &vectors {
+            let dot: f64 = vector.iter().zip(basis).map(|(a, b)| a * b).sum();
+            for (a, b) in vector.iter_mut().zip(basis) {
+                *a -= dot * b;
+            }
+        }
+        let norm = vector.iter().map(|a| a * a).sum::<f64>().sqrt();
+        if norm > 1e-10 {
+            vectors.push(vector.iter().map(|a| a / norm).collect());
+        }
+    }
+
+    let data = weights.data_mut();
+    for (i, vector) in vectors.iter().enumerate() {
+        for (j, value) in vector.iter().enumerate() {
+            if rows <= cols {
+                data[i * cols + j] = *value;
+            } else {
+                data[j * cols + i] = *value;
+            }
+        }
+    }
+}
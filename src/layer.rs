@@ -1,401 +1,1586 @@
-use std::vec;
-use serde_derive::*;
+use std::any::Any;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserializer, Serializer};
+use serde_derive::{Serialize, Deserialize};
+// A custom layer's loader turns a saved Value back into the layer, see register_layer
+pub use serde_json::Value;
+pub use crate::registry::Loader;
 
 use crate::
     {activation::
-        {Activation, ActivationFunction}, 
+        {Activation, ActivationFunction},
         conv_params::
-            {ConvParams, PaddingType}, 
+            {ConvParams, PaddingType},
         dense_params::
-            DenseParams
+            DenseParams,
+        initializer::
+            {self, Initializer},
+        registry::
+            {self, Registry},
+        tensor::
+            Tensor
     };
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum LayerType {
     Dense,
     Convolutional,
-    Pooling
+    Pooling,
+    Normalization,
+    Regularization,
+    Upsampling,
+    Custom
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Layer {
-    pub activation: Activation,
-    pub layer_type: LayerType,
-    pub conv_params: Option<ConvParams>,
-    pub dense_params: Option<DenseParams>,
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum PoolingMode {
+    #[default]
+    Max,
+    Average,
 }
 
-impl Layer {
-    pub fn dense(nodes: [usize; 2], activation_fn: ActivationFunction) -> Self {
-        let dense_params = Some(DenseParams::new(nodes[0], nodes[1]));
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum UpsampleMode {
+    Nearest,
+    Bilinear,
+}
 
-        let mut layer = Layer {
-            dense_params,
-            activation: Activation::new(activation_fn),
-            layer_type: LayerType::Dense,
-            conv_params: None
+// Everything the network needs from a layer. All forward and backward passes work on
+// batches, the first axis of every tensor is the sample
+pub trait LayerImpl: Any {
+    fn forward(&mut self, inputs: Tensor) -> Tensor;
+    // Accumulates parameter gradients and returns the delta for the previous layer, shaped like the last inputs
+    fn backward(&mut self, errors: Tensor) -> Tensor;
+    // (values, gradients) pairs, the network gives each pair its own optimizer id in this order
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)>;
+    // Per-sample output shape for a per-sample input shape
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize>;
+    fn reset(&mut self);
+    // Output activation, lets the network check it against the loss and fuse the two
+    fn activation(&self) -> Option<&Activation> {
+        None
+    }
+    // Backward pass for errors already taken w.r.t. the weighted inputs, used when the loss is
+    // fused with the output activation. Layers without an activation can keep the default
+    fn backward_preactivation(&mut self, errors: Tensor) -> Tensor {
+        self.backward(errors)
+    }
+    // Errors w.r.t. the weighted inputs of the last forward pass, without accumulating anything.
+    // Lets the network clip the delta the output layer will actually backpropagate
+    fn activation_delta(&self, errors: &Tensor) -> Tensor {
+        errors.clone()
+    }
+    // Training uses batch statistics and randomness (dropout), inference uses running statistics
+    fn set_training(&mut self, _training: bool) {}
+    // Seed drawn from the network's RNG, layers with weights or other randomness should derive all of it from here.
+    // Weights that already exist are kept
+    fn seed(&mut self, _seed: u64) {}
+    // Draws the weights again from the seed, layers without random weights can keep the default
+    fn reinit(&mut self) {}
+    // Conv and pooling layers make the network read samples as images instead of flat vectors
+    fn layer_type(&self) -> LayerType {
+        LayerType::Custom
+    }
+    // A layer is saved as its name plus its fields, weights included. Custom layers come back through register_layer
+    fn name(&self) -> &'static str;
+    fn state(&self) -> Value;
+}
+
+impl dyn LayerImpl {
+    pub fn downcast_ref<T: LayerImpl>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref::<T>()
+    }
+
+    pub fn downcast_mut<T: LayerImpl>(&mut self) -> Option<&mut T> {
+        (self as &mut dyn Any).downcast_mut::<T>()
+    }
+
+    pub fn zero_grad(&mut self) {
+        for (_, grads) in self.parameters() {
+            grads.fill(0.0);
+        }
+    }
+}
+
+// Applies the activation's vector-Jacobian product to every `row` long slice of the outputs
+fn activation_backward(activation: &mut Activation, weighted_inputs: &Tensor, outputs: &Tensor, errors: Tensor, row: usize) -> Tensor {
+    let errors = errors.reshape(outputs.shape());
+    for (error_row, input_row) in errors.data().chunks(row).zip(weighted_inputs.data().chunks(row)) {
+        activation.backward_slopes(input_row, error_row);
+    }
+    activation_delta(activation, weighted_inputs, outputs, &errors, row)
+}
+
+// Same product without the slope gradients
+fn activation_delta(activation: &Activation, weighted_inputs: &Tensor, outputs: &Tensor, errors: &Tensor, row: usize) -> Tensor {
+    let mut delta = Vec::with_capacity(errors.len());
+    for (error_row, (input_row, output_row)) in errors.data().chunks(row).zip(weighted_inputs.data().chunks(row).zip(outputs.data().chunks(row))) {
+        delta.extend(activation.backward(input_row, output_row, error_row));
+    }
+    Tensor::new(delta, outputs.shape())
+}
+
+// Softmax normalises every output row of a conv layer, other activations see whole samples so PReLU can tell the channels apart
+fn conv_activation_row(activation: &Activation, shape: &[usize]) -> usize {
+    match activation.function {
+        ActivationFunction::SoftMax | ActivationFunction::LogSoftMax => shape[3],
+        _ => shape[1..].iter().product(),
+    }
+}
+
+static LAYERS: Registry<dyn LayerImpl> = Registry::new();
+
+// Custom layers must be registered under their name() before a model using them is loaded
+pub fn register_layer(name: &str, loader: Loader<dyn LayerImpl>) {
+    LAYERS.register(name, loader);
+}
+
+fn load<T: LayerImpl + for<'de> serde::Deserialize<'de>>(state: Value) -> Result<Box<dyn LayerImpl>, serde_json::Error> {
+    Ok(Box::new(serde_json::from_value::<T>(state)?))
+}
+
+// The single Layer struct that models were saved as before layers became trait objects
+#[derive(Deserialize)]
+struct LegacyLayer {
+    activation: Activation,
+    layer_type: LayerType,
+    conv_params: Option<LegacyConvParams>,
+    dense_params: Option<LegacyDenseParams>,
+}
+
+#[derive(Deserialize)]
+struct LegacyDenseParams {
+    nodes_in: usize,
+    nodes_out: usize,
+    weights: Vec<Vec<f64>>,
+    biases: Vec<f64>,
+}
+
+// One kernel per input channel and a single bias, every channel was convolved on its own
+#[derive(Deserialize)]
+struct LegacyConvParams {
+    kernel: usize,
+    padding_type: PaddingType,
+    stride: usize,
+    weights: Vec<Vec<Vec<f64>>>,
+    bias: f64,
+}
+
+// The activation goes through Activation's own loading, so a saved "ReLU" keeps its 0.01 slope
+fn load_legacy(state: Value) -> Result<Box<dyn LayerImpl>, serde_json::Error> {
+    let legacy: LegacyLayer = serde_json::from_value(state)?;
+    let missing = |field: &str| serde::de::Error::custom(format!("{:?} layer without {}", legacy.layer_type, field));
+    match legacy.layer_type {
+        LayerType::Dense => 
+            {
+                let params = legacy.dense_params.ok_or_else(|| missing("dense_params"))?;
+                let mut layer = Dense {
+                    params: DenseParams::new(params.nodes_in, params.nodes_out),
+                    activation: legacy.activation,
+                };
+                layer.set_params(params.weights, params.biases);
+                Ok(Box::new(layer))
+            },
+        LayerType::Convolutional => 
+            {
+                // Filter c only reads channel c, and the bias used to be added once per kernel position
+                let params = legacy.conv_params.ok_or_else(|| missing("conv_params"))?;
+                let channels = params.weights.len();
+                let area = params.kernel * params.kernel;
+                let mut conv = ConvParams::new(params.kernel, channels, params.padding_type, params.stride);
+                conv.weights = Tensor::zeros(&[channels, channels, params.kernel, params.kernel]);
+                for (c, kernel) in params.weights.into_iter().enumerate() {
+                    let weights: Vec<f64> = kernel.into_iter().flatten().collect();
+                    conv.weights.slice_mut(c)[c * area..(c + 1) * area].copy_from_slice(&weights);
+                }
+                conv.biases = Tensor::filled(&[channels], params.bias * area as f64);
+                conv.grad_weights = Tensor::zeros(conv.weights.shape());
+                conv.grad_biases = Tensor::zeros(conv.biases.shape());
+                Ok(Box::new(Conv {
+                    params: conv,
+                    activation: legacy.activation,
+                }))
+            },
+        LayerType::Pooling => 
+            {
+                let params = legacy.conv_params.ok_or_else(|| missing("conv_params"))?;
+                Ok(Layer::pool(params.kernel, params.stride))
+            },
+        _ => Err(missing("a known layer_type")),
+    }
+}
+
+impl serde::Serialize for Box<dyn LayerImpl> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        registry::serialize_named(self.name(), self.state(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Box<dyn LayerImpl> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = <Value as serde::Deserialize>::deserialize(deserializer)?;
+        // Models saved before layers were trait objects have no name, see LegacyLayer
+        if state.get("layer_type").is_some() {
+            return load_legacy(state).map_err(serde::de::Error::custom);
+        }
+        let (name, state) = registry::deserialize_named(state).map_err(serde::de::Error::custom)?;
+        let layer = match name.as_str() {
+            "Dense" => load::<Dense>(state),
+            "Convolutional" => load::<Conv>(state),
+            "Pooling" => load::<Pool>(state),
+            "GlobalAveragePool" | "GlobalMaxPool" => load::<GlobalPool>(state),
+            "BatchNorm" => load::<BatchNorm>(state),
+            "LayerNorm" => load::<LayerNorm>(state),
+            "RMSNorm" => load::<RMSNorm>(state),
+            "Dropout" | "SpatialDropout" => load::<Dropout>(state),
+            "ConvTranspose" => load::<ConvTranspose>(state),
+            "Upsample" => load::<Upsample>(state),
+            _ => LAYERS.load(&name, state)
+                .ok_or_else(|| serde::de::Error::custom(format!("Unknown layer \"{}\", register it with register_layer", name)))?,
         };
-        layer.dense_params.as_mut().unwrap().init(layer.activation.function.clone());
-        layer
+        layer.map_err(serde::de::Error::custom)
+    }
+}
+
+// Constructors for the built-in layers
+pub struct Layer;
+
+impl Layer {
+    pub fn dense(nodes: [usize; 2], activation_fn: ActivationFunction) -> Box<dyn LayerImpl> {
+        Layer::dense_with(nodes, activation_fn, Initializer::default())
     }
 
-    pub fn conv(kernel: usize, padding_type: PaddingType, stride: usize, activation_fn: ActivationFunction) -> Self {
-        let conv_params = Some(ConvParams::new(kernel, padding_type, stride));
-        let layer = Layer {
-            dense_params: None,
+    pub fn dense_with(nodes: [usize; 2], activation_fn: ActivationFunction, initializer: Initializer) -> Box<dyn LayerImpl> {
+        let mut layer = Dense {
+            params: DenseParams::new(nodes[0], nodes[1]),
             activation: Activation::new(activation_fn),
-            layer_type: LayerType::Convolutional,
-            conv_params
         };
-        layer
+        layer.params.initializer = initializer;
+        layer.params.init(layer.activation.function.clone());
+        layer.activation.init(nodes[1]);
+        Box::new(layer)
     }
 
-    pub fn pool(kernel: usize, stride: usize) -> Self {
-        let conv_params = Some(ConvParams::new(kernel, PaddingType::Valid, stride));
-        let layer = Layer {
-            dense_params: None,
-            activation: Activation::new(ActivationFunction::ReLU),
-            layer_type: LayerType::Pooling,
-            conv_params
-        };
-        layer
+    pub fn conv(kernel: usize, out_channels: usize, padding_type: PaddingType, stride: usize, activation_fn: ActivationFunction) -> Box<dyn LayerImpl> {
+        Layer::conv_with(kernel, out_channels, padding_type, stride, activation_fn, Initializer::default())
     }
 
-    pub fn conv_forward(&mut self, inputs: Vec<Vec<Vec<f64>>>) -> Vec<Vec<Vec<f64>>> {
-        let params = self.conv_params.as_mut().unwrap();
-        params.inputs = inputs;
-        if params.padding_type == PaddingType::Valid {
-            params.data = params.inputs.clone();
-        }
-        params.add_padding(self.activation.function.clone());
-        let output_dims = params.get_output_dims();
+    // Conv weights are created on the first forward pass, once the input channels are known
+    pub fn conv_with(kernel: usize, out_channels: usize, padding_type: PaddingType, stride: usize, activation_fn: ActivationFunction, initializer: Initializer) -> Box<dyn LayerImpl> {
+        let mut activation = Activation::new(activation_fn);
+        activation.init(out_channels);
+        let mut params = ConvParams::new(kernel, out_channels, padding_type, stride);
+        params.initializer = initializer;
+        Box::new(Conv {
+            params,
+            activation,
+        })
+    }
 
-        let mut weighted_inputs = vec![vec![vec![0.0; output_dims[0]]; output_dims[1]]; params.data.len()];
-        let img = &params.data;
+    pub fn pool(kernel: usize, stride: usize) -> Box<dyn LayerImpl> {
+        Layer::pool_with(kernel, stride, PoolingMode::Max)
+    }
 
-        let mut activation = vec![vec![vec![0.0; output_dims[0]]; output_dims[1]]; params.data.len()];
+    pub fn pool_with(kernel: usize, stride: usize, mode: PoolingMode) -> Box<dyn LayerImpl> {
+        Box::new(Pool {
+            params: ConvParams::new(kernel, 0, PaddingType::Valid, stride),
+            mode,
+        })
+    }
 
-        for i in 0..img.len() { //each channel
-            for j in (0..weighted_inputs[i].len()) { //each img row
-                if j + params.kernel > params.data[i].len() {
-                    break;
-                }
-                for k in (0..weighted_inputs[i][j].len()) { //each img column
-                    if k + params.kernel > params.data[i][0].len() {
-                        break;
-                    }
-                    for kern_row in 0..params.kernel { //Kernel rows
-                        for kern_col in 0..params.kernel { //Kernel Columns
-                            weighted_inputs[i][j][k] += (img[i][j * params.stride + kern_row][k * params.stride + kern_col] * params.weights[i][kern_row][kern_col]);
-                            weighted_inputs[i][j][k] += params.bias;
-                        }
-                    }
+    // Reduce every channel to one value, [N, C, H, W] -> [N, C, 1, 1]
+    pub fn global_average_pool() -> Box<dyn LayerImpl> {
+        Box::new(GlobalPool::new(PoolingMode::Average))
+    }
+
+    pub fn global_max_pool() -> Box<dyn LayerImpl> {
+        Box::new(GlobalPool::new(PoolingMode::Max))
+    }
+
+    // Per feature after dense layers ([N, features]), per channel after conv layers ([N, channels, H, W])
+    pub fn batch_norm(features: usize) -> Box<dyn LayerImpl> {
+        Box::new(BatchNorm::new(features))
+    }
+
+    // Normalize each sample over all of its `features` values, independent of the batch
+    pub fn layer_norm(features: usize) -> Box<dyn LayerImpl> {
+        Box::new(LayerNorm::new(features))
+    }
+
+    pub fn rms_norm(features: usize) -> Box<dyn LayerImpl> {
+        Box::new(RMSNorm::new(features))
+    }
+
+    // Zeroes each value with probability p while training
+    pub fn dropout(p: f64) -> Box<dyn LayerImpl> {
+        Box::new(Dropout::new(p, false))
+    }
+
+    // Zeroes whole channels of conv feature maps instead of single values
+    pub fn spatial_dropout(p: f64) -> Box<dyn LayerImpl> {
+        Box::new(Dropout::new(p, true))
+    }
+
+    // Output size is (size - 1) * stride + kernel + output_padding - 2 * padding
+    pub fn conv_transpose(kernel: usize, out_channels: usize, stride: usize, padding: usize, output_padding: usize, activation_fn: ActivationFunction) -> Box<dyn LayerImpl> {
+        assert!(output_padding < stride.max(1), "Output padding must be smaller than the stride");
+        let mut activation = Activation::new(activation_fn);
+        activation.init(out_channels);
+        let mut params = ConvParams::new(kernel, out_channels, PaddingType::Valid, stride);
+        params.padding = padding;
+        Box::new(ConvTranspose {
+            params,
+            activation,
+            output_padding,
+        })
+    }
+
+    // Scales the height and width of [N, C, H, W] inputs by `scale`
+    pub fn upsample(scale: usize, mode: UpsampleMode) -> Box<dyn LayerImpl> {
+        Box::new(Upsample {
+            scale,
+            mode,
+            input_shape: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dense {
+    pub activation: Activation,
+    pub params: DenseParams,
+}
+
+impl Dense {
+    pub fn set_params(&mut self, weights: impl Into<Tensor>, biases: impl Into<Tensor>) {
+        self.params.weights = weights.into();
+        self.params.biases = biases.into();
+    }
+}
+
+impl LayerImpl for Dense {
+    // Inputs of any shape are read as [batch, nodes_in], so conv outputs need no explicit flatten
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let params = &mut self.params;
+        let batch = inputs.shape()[0];
+        assert_eq!(inputs.len(), batch * params.nodes_in, "Dense layer expects {} inputs per sample", params.nodes_in);
+        params.inputs = inputs;
+        let inputs = params.inputs.data();
+
+        let mut weighted = Vec::with_capacity(batch * params.nodes_out);
+        let mut activation = Vec::with_capacity(batch * params.nodes_out);
+        for n in 0..batch { //each sample
+            let sample = &inputs[n * params.nodes_in..(n + 1) * params.nodes_in];
+            let mut weighted_inputs = params.biases.data().to_vec();
+            for (i, weighted_input) in weighted_inputs.iter_mut().enumerate() {
+                for (j, input) in sample.iter().enumerate() {
+                    *weighted_input += input * params.weights[[j, i]];
                 }
             }
-            for j in 0..weighted_inputs[i].len() { 
-                activation[i][j] = self.activation.function(weighted_inputs[i][j].clone());
-            }
+            activation.extend(self.activation.function(&weighted_inputs));
+            weighted.extend(weighted_inputs);
         }
+
+        params.weighted_inputs = Tensor::new(weighted, &[batch, params.nodes_out]);
+        let activation = Tensor::new(activation, &[batch, params.nodes_out]);
         params.outputs = activation.clone();
         activation
     }
 
-    pub fn pool_forward(&mut self, inputs: Vec<Vec<Vec<f64>>>) -> Vec<Vec<Vec<f64>>> {
-        let params = self.conv_params.as_mut().unwrap();
-        params.inputs = inputs;
-        params.add_padding(self.activation.function.clone());
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let params = &self.params;
+        let delta = activation_backward(&mut self.activation, &params.weighted_inputs, &params.outputs, errors, params.nodes_out);
+        self.backward_preactivation(delta)
+    }
 
-        let output_dims = params.get_output_dims();
-        let img = params.inputs.clone();
+    fn activation_delta(&self, errors: &Tensor) -> Tensor {
+        let params = &self.params;
+        activation_delta(&self.activation, &params.weighted_inputs, &params.outputs, errors, params.nodes_out)
+    }
 
-        let mut output = vec![vec![vec![0.0; output_dims[0]]; output_dims[1]]; params.data.len()];
+    // Gradients are summed over the batch, scale the incoming errors to average them
+    fn backward_preactivation(&mut self, errors: Tensor) -> Tensor {
+        let params = &mut self.params;
+        let delta_output = errors.reshape(params.outputs.shape());
+        let batch = delta_output.shape()[0];
 
-        for i in 0..img.len() { //each channel
-            for j in (0..output[i].len()) { //each output img row
-                if (j) * params.kernel >= img[i].len() {
-                    break;
+        let inputs = params.inputs.data();
+        let mut next_delta = Tensor::zeros(params.inputs.shape());
+        for n in 0..batch { //each sample
+            let sample = &inputs[n * params.nodes_in..(n + 1) * params.nodes_in];
+            let delta = delta_output.slice(n);
+            let sample_delta = next_delta.slice_mut(n);
+            for (i, (input, input_delta)) in sample.iter().zip(sample_delta.iter_mut()).enumerate() {
+                for (j, output_delta) in delta.iter().enumerate() {
+                    params.grad_weights[[i, j]] += input * output_delta;
+                    *input_delta += params.weights[[i, j]] * output_delta;
                 }
-                for k in (0..output[i][j].len()) { //each output img column
-                    if (k) * params.kernel >= img[i][0].len() {
-                        break;
-                    }
-                    let mut max = f64::NEG_INFINITY;
-                    for kern_row in 0..params.kernel { //Kernel rows
-                        let row_i = j * params.stride + kern_row;
-                        if row_i >= img[i].len() {
-                            break;
-                        }
-                        for kern_col in 0..params.kernel { //Kernel Columns
-                            let col_i = k * params.stride + kern_col;
-                            if col_i >= img[i][0].len() {
-                                break;
-                            }
-                            let val = img[i][row_i][col_i];
-                            if val > max {
-                                max = val;
+            }
+
+            for (grad, output_delta) in params.grad_biases.data_mut().iter_mut().zip(delta) {
+                *grad += output_delta;
+            }
+        }
+
+        next_delta
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        let params = &mut self.params;
+        let mut parameters = vec![(&mut params.weights, &mut params.grad_weights), (&mut params.biases, &mut params.grad_biases)];
+        if !self.activation.slopes.is_empty() {
+            parameters.push((&mut self.activation.slopes, &mut self.activation.grad_slopes));
+        }
+        parameters
+    }
+
+    fn output_shape(&self, _input_shape: &[usize]) -> Vec<usize> {
+        vec![self.params.nodes_out]
+    }
+
+    fn reset(&mut self) {
+        self.params.inputs = Tensor::default();
+        self.params.weighted_inputs = Tensor::default();
+        self.params.outputs = Tensor::default();
+        self.params.init(self.activation.function.clone());
+        self.params.grad_weights.fill(0.0);
+        self.params.grad_biases.fill(0.0);
+        self.activation.init(self.params.nodes_out);
+    }
+
+    fn activation(&self) -> Option<&Activation> {
+        Some(&self.activation)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.params.seed = Some(seed);
+    }
+
+    fn reinit(&mut self) {
+        self.params.init(self.activation.function.clone());
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Dense
+    }
+
+    fn name(&self) -> &'static str {
+        "Dense"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conv {
+    pub activation: Activation,
+    pub params: ConvParams,
+}
+
+impl LayerImpl for Conv {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let params = &mut self.params;
+        params.inputs = inputs;
+        params.add_padding();
+        params.init(self.activation.function.clone(), params.inputs.shape()[1]);
+        let [out_width, out_height] = params.get_output_dims();
+
+        let img = &params.data;
+        let batch = img.shape()[0];
+        let channels = img.shape()[1];
+        let mut weighted_inputs = Tensor::zeros(&[batch, params.out_channels, out_height, out_width]);
+
+        for n in 0..batch { //each sample
+            for f in 0..params.out_channels { //each filter
+                for j in 0..out_height { //each output row
+                    for k in 0..out_width { //each output column
+                        let mut sum = params.biases[f];
+                        for c in 0..channels { //each input channel
+                            for kern_row in 0..params.kernel { //Kernel rows
+                                for kern_col in 0..params.kernel { //Kernel Columns
+                                    sum += img[[n, c, j * params.stride + kern_row, k * params.stride + kern_col]] * params.weights[[f, c, kern_row, kern_col]];
+                                }
                             }
                         }
+                        weighted_inputs[[n, f, j, k]] = sum;
                     }
-                    output[i][j][k] = max;
                 }
             }
         }
-        params.outputs = output.clone();
-        output
+
+        let activation: Vec<f64> = weighted_inputs.data()
+            .chunks(conv_activation_row(&self.activation, weighted_inputs.shape()))
+            .flat_map(|row| self.activation.function(row))
+            .collect();
+        let activation = Tensor::new(activation, weighted_inputs.shape());
+        params.weighted_inputs = weighted_inputs;
+        params.outputs = activation.clone();
+        activation
     }
 
-    pub fn dense_forward(&mut self, inputs: Vec<f64>) -> Vec<f64> {
-        self.dense_params.as_mut().unwrap().inputs = inputs.clone();
-        let mut weighted_inputs = self.dense_params.as_mut().unwrap().biases.clone();
-        for i in 0..self.dense_params.as_mut().unwrap().nodes_out {
-            for j in 0..self.dense_params.as_mut().unwrap().nodes_in {
-                weighted_inputs[i] += inputs[j] * self.dense_params.as_mut().unwrap().weights[j][i];
-            }
-        }
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let params = &self.params;
+        let row = conv_activation_row(&self.activation, params.outputs.shape());
+        let delta = activation_backward(&mut self.activation, &params.weighted_inputs, &params.outputs, errors, row);
+        self.backward_preactivation(delta)
+    }
 
-        let activation = self.activation.function(weighted_inputs);
-        self.dense_params.as_mut().unwrap().outputs = activation.clone();
-        activation
+    fn activation_delta(&self, errors: &Tensor) -> Tensor {
+        let params = &self.params;
+        let row = conv_activation_row(&self.activation, params.outputs.shape());
+        activation_delta(&self.activation, &params.weighted_inputs, &params.outputs, errors, row)
     }
 
-    pub fn conv_backward(&mut self, errors: Vec<Vec<Vec<f64>>>, learning_rate: f64) -> Vec<Vec<Vec<f64>>> {
-        let params = self.conv_params.as_mut().unwrap();
-        let channels = params.data.len();
-        let mut delta_output = errors;
-        let mut weight_gradients = vec![vec![vec![0.0; params.kernel]; params.kernel]; channels];
-        let img = params.data.clone();
-        let mut avg_bias_gradient = 0.0;
-        let mut next_delta = vec![]; //3x3
+    fn backward_preactivation(&mut self, errors: Tensor) -> Tensor {
+        let params = &mut self.params;
+        let delta_output = errors.reshape(params.outputs.shape());
         let kernel = params.kernel;
+        let stride = params.stride;
+        let out_height = delta_output.shape()[2];
+        let out_width = delta_output.shape()[3];
+        let img = &params.data;
+        let batch = img.shape()[0];
+        let channels = img.shape()[1];
+        let mut padded_delta = Tensor::zeros(img.shape());
 
-        for i in 0..channels { //each channel
-            for j in 0..delta_output[i].len() {
-                if self.activation.function == ActivationFunction::SoftMax {
-                    break;
-                }
-                let activation_derivatives = self.activation.derivative(params.outputs[i][j].clone());
-                for k in 0..delta_output[i][j].len() {
-                    delta_output[i][j][k] *= activation_derivatives[k];
-                }
-            }
-
-            for j in (0..delta_output[i].len()) { //each img row
-                if j + kernel == img[i].len() {
-                    break;
-                }
-                for k in (0..delta_output[i][j].len()) { //each img column
-                    if k + kernel == img[i][j].len() {
-                        break;
-                    }
-                    for kern_row in 0..kernel { //Kernel rows
-                        for kern_col in 0..kernel { //Kernel Columns
-                            weight_gradients[i][kern_row][kern_col] += (img[i][j * params.stride + kern_row][k * params.stride + kern_col] * delta_output[i][j][k]);
+        for n in 0..batch { //each sample
+            for f in 0..params.out_channels { //each filter
+                for j in 0..out_height { //each output row
+                    for k in 0..out_width { //each output column
+                        let delta = delta_output[[n, f, j, k]];
+                        // Each filter's bias touches every output position once
+                        params.grad_biases[f] += delta;
+                        for c in 0..channels { //each input channel
+                            for kern_row in 0..kernel { //Kernel rows
+                                for kern_col in 0..kernel { //Kernel Columns
+                                    let row_i = j * stride + kern_row;
+                                    let col_i = k * stride + kern_col;
+                                    params.grad_weights[[f, c, kern_row, kern_col]] += img[[n, c, row_i, col_i]] * delta;
+                                    padded_delta[[n, c, row_i, col_i]] += params.weights[[f, c, kern_row, kern_col]] * delta;
+                                }
+                            }
                         }
                     }
                 }
             }
-            for j in 0..params.weights[i].len() {
-                for k in 0..params.weights[i][j].len() {
-                    params.weights[i][j][k] -= learning_rate *  weight_gradients[i][j][k];
-                }
-            }
-            // Update bias using the average of the gradients
-            for j in 0..delta_output[i].len() {
-                for k in 0..delta_output[i][j].len() {
-                    avg_bias_gradient += delta_output[i][j][k];
+        }
+
+        // Strip the padding so the delta matches the layer's unpadded inputs
+        let height = params.inputs.shape()[2];
+        let width = params.inputs.shape()[3];
+        let mut next_delta = Tensor::zeros(params.inputs.shape());
+        for n in 0..batch {
+            for c in 0..channels {
+                for j in 0..height {
+                    for k in 0..width {
+                        next_delta[[n, c, j, k]] = padded_delta[[n, c, j + params.padding, k + params.padding]];
+                    }
                 }
             }
-            avg_bias_gradient /= (delta_output[i].len() * delta_output[i][0].len()) as f64;
+        }
 
-            let padded_gradients = Self::add_padding_matrix(kernel - 1, &delta_output[i]);
+        next_delta
+    }
 
-            let rotated_kernel = Self::rotate180(&params.weights[i]);
-            let mut gradient_channel = vec![];
-            for j in (0..padded_gradients.len()) { //each img row
-                if j + params.kernel > padded_gradients.len() {
-                    break;
-                }
-                let mut gradient_row = vec![];
-                for k in (0..padded_gradients[j].len()) { //each img column
-                    if k + params.kernel > padded_gradients[0].len() {
-                        break;
-                    }
-                    let mut sum = 0.0;
-                    for kern_row in 0..params.kernel { //Kernel rows
-                        for kern_col in 0..params.kernel { //Kernel Columns
-                            sum += (rotated_kernel[kern_row][kern_col] * padded_gradients[j * params.stride + kern_row][k * params.stride + kern_col]);
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        self.params.parameters(&mut self.activation)
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let [height, width] = self.params.get_output_size(input_shape[1], input_shape[2]);
+        vec![self.params.out_channels, height, width]
+    }
+
+    fn reset(&mut self) {
+        self.params.reset(&mut self.activation);
+    }
+
+    fn activation(&self) -> Option<&Activation> {
+        Some(&self.activation)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.params.seed = Some(seed);
+    }
+
+    fn reinit(&mut self) {
+        self.params.clear_weights();
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Convolutional
+    }
+
+    fn name(&self) -> &'static str {
+        "Convolutional"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pool {
+    pub params: ConvParams,
+    #[serde(default)]
+    pub mode: PoolingMode,
+}
+
+impl LayerImpl for Pool {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let params = &mut self.params;
+        params.inputs = inputs;
+        params.add_padding();
+
+        let [out_width, out_height] = params.get_output_dims();
+        let img = &params.data;
+        let batch = img.shape()[0];
+        let channels = img.shape()[1];
+
+        let mut output = Tensor::zeros(&[batch, channels, out_height, out_width]);
+
+        for n in 0..batch { //each sample
+            for i in 0..channels { //each channel
+                for j in 0..out_height { //each output img row
+                    for k in 0..out_width { //each output img column
+                        let mut max = f64::NEG_INFINITY;
+                        let mut sum = 0.0;
+                        for kern_row in 0..params.kernel { //Kernel rows
+                            for kern_col in 0..params.kernel { //Kernel Columns
+                                let val = img[[n, i, j * params.stride + kern_row, k * params.stride + kern_col]];
+                                if val > max {
+                                    max = val;
+                                }
+                                sum += val;
+                            }
                         }
+                        output[[n, i, j, k]] = match self.mode {
+                            PoolingMode::Max => max,
+                            PoolingMode::Average => sum / (params.kernel * params.kernel) as f64,
+                        };
                     }
-                    gradient_row.push(sum);
                 }
-                gradient_channel.push(gradient_row);
             }
-            next_delta.push(gradient_channel);
         }
-        params.bias -= learning_rate * avg_bias_gradient;
-
-        next_delta
+        params.outputs = output.clone();
+        output
     }
 
-    pub fn pool_backward(&mut self, errors: Vec<Vec<Vec<f64>>>) -> Vec<Vec<Vec<f64>>> {
-        let params = self.conv_params.as_mut().unwrap();
-        let channels = params.data.len();
-        let delta_output = errors;
-        let img = params.data.clone();
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let params = &mut self.params;
+        let delta_output = errors.reshape(params.outputs.shape());
+        let img = &params.data;
+        let batch = img.shape()[0];
+        let channels = img.shape()[1];
         let kernel = params.kernel;
-        let inputs = params.inputs.clone();
-        let mut indecies = vec![[0, 0]; delta_output.len() * delta_output[0].len() + delta_output[0][0].len()];
 
-        let mut next_delta = vec![vec![vec![0.0; inputs[0][0].len()]; inputs[0].len()]; inputs.len()]; //4x4
+        let mut next_delta = Tensor::zeros(params.inputs.shape());
 
-        for i in 0..channels { //each channel
-            for j in (0..delta_output[i].len()) { //each img row
-                if j * params.stride >= img[i].len() {
-                    break;
-                }
-                for k in (0..delta_output[i][j].len()) { //each img column
-                    if k * params.stride >= img[i][j].len() {
-                        break;
-                    }
-                    let mut max = f64::NEG_INFINITY;
-                    let mut max_indx = [j * params.stride, k * params.stride];
-                    for kern_row in 0..kernel { //Kernel rows
-                        let row_i = j * params.stride + kern_row;
-                        if row_i >= img[i].len() {
-                            break;
-                        }
-                        for kern_col in 0..kernel { //Kernel Columns
-                            let col_i = k * params.stride + kern_col;
-                            if col_i >= img[i][0].len() {
-                                break;
+        for n in 0..batch { //each sample
+            for i in 0..channels { //each channel
+                for j in 0..delta_output.shape()[2] { //each img row
+                    for k in 0..delta_output.shape()[3] { //each img column
+                        if self.mode == PoolingMode::Average {
+                            // Every input in the window contributed equally
+                            let share = delta_output[[n, i, j, k]] / (kernel * kernel) as f64;
+                            for kern_row in 0..kernel {
+                                for kern_col in 0..kernel {
+                                    next_delta[[n, i, j * params.stride + kern_row, k * params.stride + kern_col]] += share;
+                                }
                             }
-                            let val = img[i][row_i][col_i];
-                            if val > max {
-                                max_indx = [row_i, col_i]; //each kernel
-                                max = val;
+                            continue;
+                        }
+                        let mut max = f64::NEG_INFINITY;
+                        let mut max_indx = [j * params.stride, k * params.stride];
+                        for kern_row in 0..kernel { //Kernel rows
+                            for kern_col in 0..kernel { //Kernel Columns
+                                let row_i = j * params.stride + kern_row;
+                                let col_i = k * params.stride + kern_col;
+                                let val = img[[n, i, row_i, col_i]];
+                                if val > max {
+                                    max_indx = [row_i, col_i]; //each kernel
+                                    max = val;
+                                }
                             }
                         }
-                        // indecies.push(max_indx); //each channel
+                        next_delta[[n, i, max_indx[0], max_indx[1]]] += delta_output[[n, i, j, k]];
                     }
-                    next_delta[i][max_indx[0]][max_indx[1]] = delta_output[i][j][k];
                 }
             }
         }
         next_delta
     }
 
-    pub fn rotate180(mat: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
-        let mut new_mat = mat.clone();
-        for row in &mut new_mat {
-            row.reverse();
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![]
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let [height, width] = self.params.get_output_size(input_shape[1], input_shape[2]);
+        vec![input_shape[0], height, width]
+    }
+
+    fn reset(&mut self) {
+        self.params.inputs = Tensor::default();
+        self.params.outputs = Tensor::default();
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Pooling
+    }
+
+    fn name(&self) -> &'static str {
+        "Pooling"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GlobalPool {
+    pub mode: PoolingMode,
+    pub inputs: Tensor,
+}
+
+impl GlobalPool {
+    pub fn new(mode: PoolingMode) -> Self {
+        GlobalPool {
+            mode,
+            inputs: Tensor::default(),
+        }
+    }
+}
+
+impl LayerImpl for GlobalPool {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let [batch, channels] = [inputs.shape()[0], inputs.shape()[1]];
+        let area = inputs.len() / (batch * channels);
+        let output: Vec<f64> = inputs.data().chunks(area)
+            .map(|plane| match self.mode {
+                PoolingMode::Max => plane.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                PoolingMode::Average => plane.iter().sum::<f64>() / area as f64,
+            })
+            .collect();
+        self.inputs = inputs;
+        Tensor::new(output, &[batch, channels, 1, 1])
+    }
+
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let area = self.inputs.len() / errors.len();
+        let mut next_delta = Tensor::zeros(self.inputs.shape());
+        let planes = self.inputs.data().chunks(area).zip(next_delta.data_mut().chunks_mut(area));
+        for ((plane, delta), error) in planes.zip(errors.data()) {
+            match self.mode {
+                PoolingMode::Max =>
+                    {
+                        // The first maximum takes the whole error, as in Pool
+                        let mut max_indx = 0;
+                        for (i, val) in plane.iter().enumerate() {
+                            if *val > plane[max_indx] {
+                                max_indx = i;
+                            }
+                        }
+                        delta[max_indx] = *error;
+                    },
+                PoolingMode::Average => delta.fill(error / area as f64),
+            }
+        }
+        next_delta
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![]
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![input_shape[0], 1, 1]
+    }
+
+    fn reset(&mut self) {
+        self.inputs = Tensor::default();
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Pooling
+    }
+
+    fn name(&self) -> &'static str {
+        match self.mode {
+            PoolingMode::Max => "GlobalMaxPool",
+            PoolingMode::Average => "GlobalAveragePool",
         }
-        new_mat.reverse();
-        new_mat
     }
 
-    pub fn add_padding_matrix(padding: usize, matrix: &Vec<Vec<f64>>) -> Vec<Vec<f64>> {
-        let height = matrix.len();
-        let width = matrix[0].len();
-        let padded_height = height + 2 * padding;
-        let padded_width = width + 2 * padding;
-        
-        let mut padded_image = vec![vec![0.0; padded_width]; padded_height];
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
 
-        for i in 0..height {
-            for j in 0..width {
-                padded_image[i + padding][j + padding] = matrix[i][j];
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchNorm {
+    pub gamma: Tensor,
+    pub beta: Tensor,
+    pub grad_gamma: Tensor,
+    pub grad_beta: Tensor,
+    pub running_mean: Tensor,
+    pub running_var: Tensor,
+    pub momentum: f64, //weight of the newest batch in the running statistics
+    pub epsilon: f64,
+    pub training: bool,
+    pub normalized: Tensor, //inputs after normalization, before gamma and beta
+    pub std_inv: Tensor, //1 / sqrt(var + epsilon) per feature
+    batch_stats: bool, //whether the last forward normalized with the batch's own statistics
+}
+
+impl BatchNorm {
+    pub fn new(features: usize) -> Self {
+        BatchNorm {
+            gamma: Tensor::filled(&[features], 1.0),
+            beta: Tensor::zeros(&[features]),
+            grad_gamma: Tensor::zeros(&[features]),
+            grad_beta: Tensor::zeros(&[features]),
+            running_mean: Tensor::zeros(&[features]),
+            running_var: Tensor::filled(&[features], 1.0),
+            momentum: 0.1,
+            epsilon: 1e-5,
+            training: false,
+            normalized: Tensor::default(),
+            std_inv: Tensor::default(),
+            batch_stats: false,
+        }
+    }
+
+    // Inputs are read as [N, features, rest], every feature is normalized over N and rest
+    fn feature_indices(shape: &[usize], feature: usize, features: usize) -> Vec<usize> {
+        let rest = shape[1..].iter().product::<usize>() / features;
+        (0..shape[0]).flat_map(|n| {
+            let start = (n * features + feature) * rest;
+            start..start + rest
+        }).collect()
+    }
+}
+
+impl LayerImpl for BatchNorm {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let features = self.gamma.len();
+        assert_eq!(inputs.shape()[1], features, "BatchNorm expects {} features or channels", features);
+        // A single value per feature has zero variance and would normalize to 0, so the running statistics are used instead
+        self.batch_stats = self.training && inputs.len() / features > 1;
+
+        let mut std_inv = Vec::with_capacity(features);
+        let mut normalized = Tensor::zeros(inputs.shape());
+        let mut outputs = Tensor::zeros(inputs.shape());
+        for f in 0..features {
+            let indices = BatchNorm::feature_indices(inputs.shape(), f, features);
+            let count = indices.len() as f64;
+            let (mean, var) = if self.batch_stats {
+                let mean = indices.iter().map(|j| inputs.data()[*j]).sum::<f64>() / count;
+                let var = indices.iter().map(|j| (inputs.data()[*j] - mean).powi(2)).sum::<f64>() / count;
+                // The running variance is unbiased, as it estimates the population
+                let unbiased = var * count / (count - 1.0);
+                self.running_mean[f] = (1.0 - self.momentum) * self.running_mean[f] + self.momentum * mean;
+                self.running_var[f] = (1.0 - self.momentum) * self.running_var[f] + self.momentum * unbiased;
+                (mean, var)
+            } else {
+                (self.running_mean[f], self.running_var[f])
+            };
+
+            let inv = 1.0 / (var + self.epsilon).sqrt();
+            for j in indices {
+                normalized.data_mut()[j] = (inputs.data()[j] - mean) * inv;
+                outputs.data_mut()[j] = self.gamma[f] * normalized.data()[j] + self.beta[f];
             }
+            std_inv.push(inv);
         }
-        padded_image
+
+        self.std_inv = Tensor::new(std_inv, &[features]);
+        self.normalized = normalized;
+        outputs
     }
 
-    pub fn dense_backward(&mut self, errors: Vec<f64>, learning_rate: f64) -> Vec<f64> {
-        let mut delta_output = errors.clone();
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let features = self.gamma.len();
+        let errors = errors.reshape(self.normalized.shape());
 
-        let activation_gradients = self.activation.derivative(self.dense_params.as_mut().unwrap().outputs.clone());
-        for i in 0..delta_output.len() {
-            if self.activation.function == ActivationFunction::SoftMax {
-                break;
+        let mut next_delta = Tensor::zeros(errors.shape());
+        for f in 0..features {
+            let indices = BatchNorm::feature_indices(errors.shape(), f, features);
+            let count = indices.len() as f64;
+            let sum_errors: f64 = indices.iter().map(|j| errors.data()[*j]).sum();
+            let sum_scaled: f64 = indices.iter().map(|j| errors.data()[*j] * self.normalized.data()[*j]).sum();
+            self.grad_gamma[f] += sum_scaled;
+            self.grad_beta[f] += sum_errors;
+
+            let scale = self.gamma[f] * self.std_inv[f];
+            for j in indices {
+                next_delta.data_mut()[j] = if self.batch_stats {
+                    // Batch statistics depend on every input, so their gradient flows back as well
+                    scale * (errors.data()[j] - sum_errors / count - self.normalized.data()[j] * sum_scaled / count)
+                } else {
+                    scale * errors.data()[j]
+                };
             }
-            delta_output[i] *= activation_gradients[i];
         }
+        next_delta
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![(&mut self.gamma, &mut self.grad_gamma), (&mut self.beta, &mut self.grad_beta)]
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+
+    fn reset(&mut self) {
+        *self = BatchNorm {
+            momentum: self.momentum,
+            epsilon: self.epsilon,
+            training: self.training,
+            ..BatchNorm::new(self.gamma.len())
+        };
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Normalization
+    }
+
+    fn name(&self) -> &'static str {
+        "BatchNorm"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerNorm {
+    pub gamma: Tensor,
+    pub beta: Tensor,
+    pub grad_gamma: Tensor,
+    pub grad_beta: Tensor,
+    pub epsilon: f64,
+    pub normalized: Tensor, //inputs after normalization, before gamma and beta
+    pub std_inv: Tensor, //1 / sqrt(var + epsilon) per sample
+}
+
+impl LayerNorm {
+    pub fn new(features: usize) -> Self {
+        LayerNorm {
+            gamma: Tensor::filled(&[features], 1.0),
+            beta: Tensor::zeros(&[features]),
+            grad_gamma: Tensor::zeros(&[features]),
+            grad_beta: Tensor::zeros(&[features]),
+            epsilon: 1e-5,
+            normalized: Tensor::default(),
+            std_inv: Tensor::default(),
+        }
+    }
+}
+
+impl LayerImpl for LayerNorm {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let features = self.gamma.len();
+        let batch = inputs.shape()[0];
+        assert_eq!(inputs.len(), batch * features, "LayerNorm expects {} values per sample", features);
+
+        let mut std_inv = Vec::with_capacity(batch);
+        let mut normalized = Vec::with_capacity(inputs.len());
+        let mut outputs = Vec::with_capacity(inputs.len());
+        for sample in inputs.data().chunks(features) {
+            let mean = sample.iter().sum::<f64>() / features as f64;
+            let var = sample.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / features as f64;
+            let inv = 1.0 / (var + self.epsilon).sqrt();
+            for ((x, gamma), beta) in sample.iter().zip(self.gamma.data()).zip(self.beta.data()) {
+                let x_hat = (x - mean) * inv;
+                normalized.push(x_hat);
+                outputs.push(gamma * x_hat + beta);
+            }
+            std_inv.push(inv);
+        }
+
+        self.std_inv = Tensor::new(std_inv, &[batch]);
+        self.normalized = Tensor::new(normalized, inputs.shape());
+        Tensor::new(outputs, inputs.shape())
+    }
 
-        for i in 0..self.dense_params.as_mut().unwrap().weights.len() {
-            for j in 0..self.dense_params.as_mut().unwrap().weights[i].len() {
-                self.dense_params.as_mut().unwrap().weights[i][j] -= learning_rate * (self.dense_params.as_mut().unwrap().inputs[i] * delta_output[j]);
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let features = self.gamma.len();
+        let count = features as f64;
+        let mut next_delta = Vec::with_capacity(errors.len());
+        for ((error, x_hat), inv) in errors.data().chunks(features).zip(self.normalized.data().chunks(features)).zip(self.std_inv.data()) {
+            let scaled: Vec<f64> = error.iter().zip(self.gamma.data()).map(|(e, gamma)| e * gamma).collect();
+            let sum_scaled: f64 = scaled.iter().sum();
+            let sum_projected: f64 = scaled.iter().zip(x_hat).map(|(e, x)| e * x).sum();
+            for (i, (e, x)) in error.iter().zip(x_hat).enumerate() {
+                self.grad_gamma[i] += e * x;
+                self.grad_beta[i] += e;
+                next_delta.push(inv * (scaled[i] - sum_scaled / count - x * sum_projected / count));
             }
         }
+        Tensor::new(next_delta, self.normalized.shape())
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![(&mut self.gamma, &mut self.grad_gamma), (&mut self.beta, &mut self.grad_beta)]
+    }
 
-        for i in 0..self.dense_params.as_mut().unwrap().biases.len() {
-            self.dense_params.as_mut().unwrap().biases[i] -= learning_rate * delta_output[i];
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
+    }
+
+    fn reset(&mut self) {
+        *self = LayerNorm {
+            epsilon: self.epsilon,
+            ..LayerNorm::new(self.gamma.len())
+        };
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Normalization
+    }
+
+    fn name(&self) -> &'static str {
+        "LayerNorm"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+// LayerNorm without centering, only scaled by the root mean square, so it has no shift either
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RMSNorm {
+    pub gamma: Tensor,
+    pub grad_gamma: Tensor,
+    pub epsilon: f64,
+    pub inputs: Tensor,
+    pub rms_inv: Tensor, //1 / sqrt(mean(x^2) + epsilon) per sample
+}
+
+impl RMSNorm {
+    pub fn new(features: usize) -> Self {
+        RMSNorm {
+            gamma: Tensor::filled(&[features], 1.0),
+            grad_gamma: Tensor::zeros(&[features]),
+            epsilon: 1e-5,
+            inputs: Tensor::default(),
+            rms_inv: Tensor::default(),
         }
+    }
+}
+
+impl LayerImpl for RMSNorm {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let features = self.gamma.len();
+        let batch = inputs.shape()[0];
+        assert_eq!(inputs.len(), batch * features, "RMSNorm expects {} values per sample", features);
 
-        let mut next_delta = vec![0.0; self.dense_params.as_mut().unwrap().nodes_in];
-        for i in 0..self.dense_params.as_mut().unwrap().weights.len() {
-            for j in 0..self.dense_params.as_mut().unwrap().weights[i].len() {
-                next_delta[i] += (self.dense_params.as_mut().unwrap().weights[i][j] * delta_output[j] * self.dense_params.as_mut().unwrap().inputs[i]);
-            }   
+        let mut rms_inv = Vec::with_capacity(batch);
+        let mut outputs = Vec::with_capacity(inputs.len());
+        for sample in inputs.data().chunks(features) {
+            let mean_square = sample.iter().map(|x| x * x).sum::<f64>() / features as f64;
+            let inv = 1.0 / (mean_square + self.epsilon).sqrt();
+            outputs.extend(sample.iter().zip(self.gamma.data()).map(|(x, gamma)| gamma * x * inv));
+            rms_inv.push(inv);
         }
 
-        next_delta
+        self.rms_inv = Tensor::new(rms_inv, &[batch]);
+        let outputs = Tensor::new(outputs, inputs.shape());
+        self.inputs = inputs;
+        outputs
     }
 
-    pub fn get_dense_weights(&self) -> Vec<Vec<f64>> {
-        return self.dense_params.as_ref().unwrap().weights.clone();
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let features = self.gamma.len();
+        let mut next_delta = Vec::with_capacity(errors.len());
+        for ((error, sample), inv) in errors.data().chunks(features).zip(self.inputs.data().chunks(features)).zip(self.rms_inv.data()) {
+            let scaled: Vec<f64> = error.iter().zip(self.gamma.data()).map(|(e, gamma)| e * gamma).collect();
+            let projected: f64 = scaled.iter().zip(sample).map(|(e, x)| e * x).sum::<f64>() / features as f64;
+            for (i, (e, x)) in error.iter().zip(sample).enumerate() {
+                self.grad_gamma[i] += e * x * inv;
+                next_delta.push(inv * scaled[i] - x * inv.powi(3) * projected);
+            }
+        }
+        Tensor::new(next_delta, self.inputs.shape())
     }
 
-    pub fn get_dense_biases(&self) -> Vec<f64> {
-        return self.dense_params.as_ref().unwrap().biases.clone();
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![(&mut self.gamma, &mut self.grad_gamma)]
     }
 
-    pub fn get_conv_weights(&self) -> Vec<Vec<Vec<f64>>> {
-        return self.conv_params.as_ref().unwrap().weights.clone();
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
     }
 
-    pub fn get_conv_biases(&self) -> f64 {
-        return self.conv_params.as_ref().unwrap().bias.clone();
+    fn reset(&mut self) {
+        *self = RMSNorm {
+            epsilon: self.epsilon,
+            ..RMSNorm::new(self.gamma.len())
+        };
     }
 
-    pub fn get_nodes(&self) -> usize {
-        if self.layer_type == LayerType::Dense {
-            return self.dense_params.as_ref().unwrap().nodes_out.clone();
+    fn layer_type(&self) -> LayerType {
+        LayerType::Normalization
+    }
+
+    fn name(&self) -> &'static str {
+        "RMSNorm"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+// Inverted dropout, kept values are scaled by 1 / (1 - p) so inference needs no rescaling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dropout {
+    pub p: f64,
+    pub spatial: bool, //one draw per channel of [N, C, H, W] inputs
+    pub training: bool,
+    pub mask: Tensor, //empty when the last forward was in inference mode
+    pub seed: Option<u64>,
+    #[serde(skip)]
+    rng: Option<StdRng>,
+}
+
+impl Dropout {
+    pub fn new(p: f64, spatial: bool) -> Self {
+        assert!((0.0..1.0).contains(&p), "Dropout probability must be in [0, 1)");
+        Dropout {
+            p,
+            spatial,
+            training: false,
+            mask: Tensor::default(),
+            seed: None,
+            rng: None,
         }
-        0
     }
+}
+
+impl LayerImpl for Dropout {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        if !self.training || self.p == 0.0 {
+            self.mask = Tensor::default();
+            return inputs;
+        }
+        // Values sharing one draw, a whole feature map for spatial dropout
+        let group = if self.spatial { inputs.shape()[2..].iter().product() } else { 1 };
+        let keep = 1.0 - self.p;
+        let seed = self.seed;
+        let rng = self.rng.get_or_insert_with(|| initializer::seeded_rng(seed));
 
-    pub fn get_input_nodes(&self) -> usize {
-        if self.layer_type == LayerType::Dense {
-            return self.dense_params.as_ref().unwrap().nodes_in.clone();
+        let mut mask = Vec::with_capacity(inputs.len());
+        for _ in 0..inputs.len() / group {
+            let scale = if rng.gen::<f64>() < self.p { 0.0 } else { 1.0 / keep };
+            mask.extend(std::iter::repeat_n(scale, group));
         }
-        0
+        let outputs: Vec<f64> = inputs.data().iter().zip(&mask).map(|(x, m)| x * m).collect();
+        self.mask = Tensor::new(mask, inputs.shape());
+        Tensor::new(outputs, inputs.shape())
+    }
+
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        if self.mask.is_empty() {
+            return errors;
+        }
+        let delta: Vec<f64> = errors.data().iter().zip(self.mask.data()).map(|(e, m)| e * m).collect();
+        Tensor::new(delta, self.mask.shape())
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![]
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        input_shape.to_vec()
     }
 
-    pub fn get_dense_outputs(&self) -> Vec<f64> {
-        self.dense_params.as_ref().unwrap().outputs.clone()
+    fn reset(&mut self) {
+        self.mask = Tensor::default();
+        self.rng = None;
     }
 
-    pub fn get_conv_outputs(&self) -> Vec<Vec<Vec<f64>>> {
-        self.conv_params.as_ref().unwrap().outputs.clone()
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
     }
 
-    pub fn get_layer_type(&self) -> LayerType {
-        self.layer_type.clone()
+    fn seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.rng = Some(StdRng::seed_from_u64(seed));
     }
 
-    pub fn reset(&mut self) {
-        if self.layer_type == LayerType::Dense {
-            self.dense_params.as_mut().unwrap().inputs = vec![];
-            self.dense_params.as_mut().unwrap().outputs = vec![];
-            self.dense_params.as_mut().unwrap().init(self.activation.function.clone());
+    fn layer_type(&self) -> LayerType {
+        LayerType::Regularization
+    }
+
+    fn name(&self) -> &'static str {
+        if self.spatial {
+            "SpatialDropout"
         } else {
-            self.conv_params.as_mut().unwrap().inputs = vec![];
-            self.conv_params.as_mut().unwrap().outputs = vec![];
-            self.conv_params.as_mut().unwrap().init(self.activation.function.clone());
+            "Dropout"
         }
     }
 
-    pub fn set_params(&mut self, weights: Vec<Vec<f64>>, biases: Vec<f64>) {
-        self.dense_params.as_mut().unwrap().weights = weights;
-        self.dense_params.as_mut().unwrap().biases = biases;
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+// Fractionally-strided convolution, every input value scatters a weighted kernel into the output.
+// Weights keep the Conv layout [out channel, in channel, kernel rows, kernel cols]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConvTranspose {
+    pub activation: Activation,
+    pub params: ConvParams, //params.padding is cropped from every border of the full output
+    pub output_padding: usize, //extra rows and columns on the bottom and right
+}
+
+impl ConvTranspose {
+    pub fn get_output_size(&self, height: usize, width: usize) -> [usize; 2] {
+        let size = |input: usize| ((input - 1) * self.params.stride + self.params.kernel + self.output_padding)
+            .checked_sub(2 * self.params.padding)
+            .expect("ConvTranspose padding is larger than its output");
+        [size(height), size(width)]
+    }
+
+    // Output position reached from input (j, k) through kernel position (kern_row, kern_col), if it survives the cropping
+    fn target(&self, j: usize, k: usize, kern_row: usize, kern_col: usize, out_height: usize, out_width: usize) -> Option<[usize; 2]> {
+        let row = (j * self.params.stride + kern_row).checked_sub(self.params.padding)?;
+        let col = (k * self.params.stride + kern_col).checked_sub(self.params.padding)?;
+        (row < out_height && col < out_width).then_some([row, col])
     }
-}
\ No newline at end of file
+}
+
+impl LayerImpl for ConvTranspose {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        self.params.inputs = inputs;
+        self.params.init(self.activation.function.clone(), self.params.inputs.shape()[1]);
+        let [batch, channels, height, width] = [0, 1, 2, 3].map(|axis| self.params.inputs.shape()[axis]);
+        let [out_height, out_width] = self.get_output_size(height, width);
+        let out_channels = self.params.out_channels;
+        let kernel = self.params.kernel;
+
+        let mut weighted_inputs = Tensor::zeros(&[batch, out_channels, out_height, out_width]);
+        for n in 0..batch { //each sample
+            for f in 0..out_channels { //each filter
+                weighted_inputs.slice_mut(n)[f * out_height * out_width..(f + 1) * out_height * out_width].fill(self.params.biases[f]);
+            }
+            for c in 0..channels { //each input channel
+                for j in 0..height { //each input row
+                    for k in 0..width { //each input column
+                        let input = self.params.inputs[[n, c, j, k]];
+                        for kern_row in 0..kernel { //Kernel rows
+                            for kern_col in 0..kernel { //Kernel Columns
+                                if let Some([row, col]) = self.target(j, k, kern_row, kern_col, out_height, out_width) {
+                                    for f in 0..out_channels {
+                                        weighted_inputs[[n, f, row, col]] += input * self.params.weights[[f, c, kern_row, kern_col]];
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let activation: Vec<f64> = weighted_inputs.data()
+            .chunks(conv_activation_row(&self.activation, weighted_inputs.shape()))
+            .flat_map(|row| self.activation.function(row))
+            .collect();
+        let activation = Tensor::new(activation, weighted_inputs.shape());
+        self.params.weighted_inputs = weighted_inputs;
+        self.params.outputs = activation.clone();
+        activation
+    }
+
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let params = &self.params;
+        let row = conv_activation_row(&self.activation, params.outputs.shape());
+        let delta = activation_backward(&mut self.activation, &params.weighted_inputs, &params.outputs, errors, row);
+        self.backward_preactivation(delta)
+    }
+
+    fn activation_delta(&self, errors: &Tensor) -> Tensor {
+        let params = &self.params;
+        let row = conv_activation_row(&self.activation, params.outputs.shape());
+        activation_delta(&self.activation, &params.weighted_inputs, &params.outputs, errors, row)
+    }
+
+    fn backward_preactivation(&mut self, errors: Tensor) -> Tensor {
+        let delta_output = errors.reshape(self.params.outputs.shape());
+        let [batch, channels, height, width] = [0, 1, 2, 3].map(|axis| self.params.inputs.shape()[axis]);
+        let [out_height, out_width] = [delta_output.shape()[2], delta_output.shape()[3]];
+        let out_channels = self.params.out_channels;
+        let kernel = self.params.kernel;
+
+        for n in 0..batch {
+            for f in 0..out_channels {
+                let plane = &delta_output.slice(n)[f * out_height * out_width..(f + 1) * out_height * out_width];
+                self.params.grad_biases[f] += plane.iter().sum::<f64>();
+            }
+        }
+
+        let mut next_delta = Tensor::zeros(self.params.inputs.shape());
+        for n in 0..batch { //each sample
+            for c in 0..channels { //each input channel
+                for j in 0..height { //each input row
+                    for k in 0..width { //each input column
+                        let input = self.params.inputs[[n, c, j, k]];
+                        let mut input_delta = 0.0;
+                        for kern_row in 0..kernel { //Kernel rows
+                            for kern_col in 0..kernel { //Kernel Columns
+                                if let Some([row, col]) = self.target(j, k, kern_row, kern_col, out_height, out_width) {
+                                    for f in 0..out_channels {
+                                        let delta = delta_output[[n, f, row, col]];
+                                        self.params.grad_weights[[f, c, kern_row, kern_col]] += input * delta;
+                                        input_delta += self.params.weights[[f, c, kern_row, kern_col]] * delta;
+                                    }
+                                }
+                            }
+                        }
+                        next_delta[[n, c, j, k]] = input_delta;
+                    }
+                }
+            }
+        }
+        next_delta
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        self.params.parameters(&mut self.activation)
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        let [height, width] = self.get_output_size(input_shape[1], input_shape[2]);
+        vec![self.params.out_channels, height, width]
+    }
+
+    fn reset(&mut self) {
+        self.params.reset(&mut self.activation);
+    }
+
+    fn activation(&self) -> Option<&Activation> {
+        Some(&self.activation)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.params.seed = Some(seed);
+    }
+
+    fn reinit(&mut self) {
+        self.params.clear_weights();
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Convolutional
+    }
+
+    fn name(&self) -> &'static str {
+        "ConvTranspose"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upsample {
+    pub scale: usize,
+    pub mode: UpsampleMode,
+    pub input_shape: Vec<usize>,
+}
+
+impl Upsample {
+    // Input positions and weights that make up output position `out` along one axis.
+    // Bilinear samples at pixel centers, so borders repeat the edge values
+    fn sources(&self, out: usize, size: usize) -> [(usize, f64); 2] {
+        match self.mode {
+            UpsampleMode::Nearest => [(out / self.scale, 1.0), (out / self.scale, 0.0)],
+            UpsampleMode::Bilinear =>
+                {
+                    let position = ((out as f64 + 0.5) / self.scale as f64 - 0.5).max(0.0);
+                    let low = (position.floor() as usize).min(size - 1);
+                    let high = (low + 1).min(size - 1);
+                    let weight = position - low as f64;
+                    [(low, 1.0 - weight), (high, weight)]
+                },
+        }
+    }
+}
+
+impl LayerImpl for Upsample {
+    fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let [batch, channels, height, width] = [0, 1, 2, 3].map(|axis| inputs.shape()[axis]);
+        let [out_height, out_width] = [height * self.scale, width * self.scale];
+
+        let mut outputs = Tensor::zeros(&[batch, channels, out_height, out_width]);
+        for n in 0..batch { //each sample
+            for c in 0..channels { //each channel
+                for j in 0..out_height { //each output row
+                    for k in 0..out_width { //each output column
+                        let mut sum = 0.0;
+                        for (row, row_weight) in self.sources(j, height) {
+                            for (col, col_weight) in self.sources(k, width) {
+                                sum += row_weight * col_weight * inputs[[n, c, row, col]];
+                            }
+                        }
+                        outputs[[n, c, j, k]] = sum;
+                    }
+                }
+            }
+        }
+        self.input_shape = inputs.shape().to_vec();
+        outputs
+    }
+
+    fn backward(&mut self, errors: Tensor) -> Tensor {
+        let [batch, channels, height, width] = [0, 1, 2, 3].map(|axis| self.input_shape[axis]);
+        let errors = errors.reshape(&[batch, channels, height * self.scale, width * self.scale]);
+
+        let mut next_delta = Tensor::zeros(&self.input_shape);
+        for n in 0..batch { //each sample
+            for c in 0..channels { //each channel
+                for j in 0..height * self.scale { //each output row
+                    for k in 0..width * self.scale { //each output column
+                        let error = errors[[n, c, j, k]];
+                        for (row, row_weight) in self.sources(j, height) {
+                            for (col, col_weight) in self.sources(k, width) {
+                                next_delta[[n, c, row, col]] += row_weight * col_weight * error;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        next_delta
+    }
+
+    fn parameters(&mut self) -> Vec<(&mut Tensor, &mut Tensor)> {
+        vec![]
+    }
+
+    fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        vec![input_shape[0], input_shape[1] * self.scale, input_shape[2] * self.scale]
+    }
+
+    fn reset(&mut self) {
+        self.input_shape = vec![];
+    }
+
+    fn layer_type(&self) -> LayerType {
+        LayerType::Upsampling
+    }
+
+    fn name(&self) -> &'static str {
+        "Upsample"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(shape: &[usize], step: usize) -> Tensor {
+        let len: usize = shape.iter().product();
+        Tensor::new((0..len).map(|i| ((i * step + 3) % 11) as f64 / 5.5 - 1.0).collect(), shape)
+    }
+
+    fn objective(layer: &mut dyn LayerImpl, inputs: &Tensor, errors: &Tensor) -> f64 {
+        layer.forward(inputs.clone()).data().iter().zip(errors.data()).map(|(y, e)| y * e).sum()
+    }
+
+    fn assert_close(what: &str, analytic: f64, numeric: f64) {
+        assert!((analytic - numeric).abs() < 1e-6 * numeric.abs().max(1.0), "{}: {} vs {}", what, analytic, numeric);
+    }
+
+    // backward against central differences of sum(errors * forward(inputs)), for the inputs and every parameter
+    fn check_layer(mut layer: Box<dyn LayerImpl>, shape: &[usize]) {
+        let inputs = pattern(shape, 7);
+        let outputs = layer.forward(inputs.clone());
+        let errors = pattern(outputs.shape(), 5);
+        layer.zero_grad();
+        layer.forward(inputs.clone());
+        let delta = layer.backward(errors.clone());
+        let eps = 1e-6;
+
+        for i in 0..inputs.len() {
+            let mut plus = inputs.clone();
+            let mut minus = inputs.clone();
+            plus[i] += eps;
+            minus[i] -= eps;
+            let numeric = (objective(layer.as_mut(), &plus, &errors) - objective(layer.as_mut(), &minus, &errors)) / (2.0 * eps);
+            assert_close(&format!("{} input {}", layer.name(), i), delta[i], numeric);
+        }
+
+        let grads: Vec<Tensor> = layer.parameters().into_iter().map(|(_, grads)| grads.clone()).collect();
+        for (p, grad) in grads.iter().enumerate() {
+            for i in 0..grad.len() {
+                layer.parameters()[p].0[i] += eps;
+                let plus = objective(layer.as_mut(), &inputs, &errors);
+                layer.parameters()[p].0[i] -= 2.0 * eps;
+                let minus = objective(layer.as_mut(), &inputs, &errors);
+                layer.parameters()[p].0[i] += eps;
+                assert_close(&format!("{} parameter {} [{}]", layer.name(), p, i), grad[i], (plus - minus) / (2.0 * eps));
+            }
+        }
+    }
+
+    #[test]
+    fn softmax_layers_backpropagate_the_jacobian() {
+        check_layer(Layer::dense([4, 3], ActivationFunction::SoftMax), &[2, 4]);
+        check_layer(Layer::conv(3, 2, PaddingType::Same, 1, ActivationFunction::SoftMax), &[2, 2, 4, 4]);
+    }
+    fn batch_norm(training: bool) -> Box<dyn LayerImpl> {
+        let mut layer = Layer::batch_norm(3);
+        layer.set_training(training);
+        layer
+    }
+
+    #[test]
+    fn batch_norm_backpropagates_through_its_statistics() {
+        check_layer(batch_norm(true), &[4, 3]);
+        check_layer(batch_norm(true), &[2, 3, 2, 2]);
+        check_layer(batch_norm(false), &[4, 3]);
+    }
+
+    #[test]
+    fn batch_norm_uses_running_statistics_for_a_single_sample() {
+        let mut layer = batch_norm(true);
+        let outputs = layer.forward(pattern(&[1, 3], 7));
+        assert!(outputs.data().iter().any(|y| y.abs() > 0.1));
+        assert!(layer.backward(Tensor::filled(&[1, 3], 1.0)).data().iter().all(|d| d.abs() > 0.1));
+        check_layer(batch_norm(true), &[1, 3]);
+    }
+    #[test]
+    fn layer_norms_backpropagate_through_their_statistics() {
+        check_layer(Layer::layer_norm(4), &[2, 4]);
+        check_layer(Layer::rms_norm(4), &[2, 4]);
+        check_layer(Layer::layer_norm(8), &[2, 2, 2, 2]);
+        check_layer(Layer::rms_norm(8), &[2, 2, 2, 2]);
+    }
+    #[test]
+    fn upsampling_layers_backpropagate() {
+        check_layer(Layer::conv_transpose(3, 2, 2, 1, 1, ActivationFunction::TanH), &[2, 2, 3, 3]);
+        check_layer(Layer::conv_transpose(2, 3, 1, 0, 0, ActivationFunction::PReLU), &[1, 2, 2, 3]);
+        check_layer(Layer::upsample(2, UpsampleMode::Nearest), &[2, 2, 2, 3]);
+        check_layer(Layer::upsample(2, UpsampleMode::Bilinear), &[2, 2, 2, 3]);
+    }
+}
@@ -1,7 +1,8 @@
-use crate::{activation::{Activation, ActivationFunction}, conv_params::PaddingType, layer::Layer};
+use crate::{activation::ActivationFunction, conv_params::PaddingType, layer::{Layer, LayerImpl}};
 
 pub struct LayerBuilder {
     kernels: Vec<usize>,
+    channels: Vec<usize>,
     paddings: Vec<PaddingType>,
     strides: Vec<usize>,
     activations: Vec<ActivationFunction>,
@@ -10,10 +11,17 @@ pub struct LayerBuilder {
     dense_layers: Vec<usize>,
 }
 
+impl Default for LayerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LayerBuilder {
     pub fn new() -> Self {
         LayerBuilder {
             kernels: vec![],
+            channels: vec![],
             paddings: vec![],
             strides: vec![],
             activations: vec![],
@@ -27,6 +35,10 @@ impl LayerBuilder {
         self.kernels = kernels;
     }
 
+    pub fn set_channels(&mut self, channels: Vec<usize>) {
+        self.channels = channels;
+    }
+
     pub fn set_paddings(&mut self, paddings: Vec<PaddingType>) {
         self.paddings = paddings;
     }
@@ -51,11 +63,11 @@ impl LayerBuilder {
         self.dense_layers = dense_layers;
     }
 
-    pub fn cnn(&self) -> Vec<Layer> {
-        let width = self.img[0];
-        let width = self.img[1];
+    pub fn cnn(&self) -> Vec<Box<dyn LayerImpl>> {
+        let _width = self.img[0];
+        let _height = self.img[1];
         let mut layers = vec![
-            Layer::conv(self.kernels[0].clone(), self.paddings[0].clone(), self.strides[0].clone(), self.activations[0].clone())
+            Layer::conv(self.kernels[0], self.channels[0], self.paddings[0].clone(), self.strides[0], self.activations[0].clone())
         ];
         let layer_count = self.cn_layers + self.dense_layers.len();
         let mut switch = false;
@@ -65,7 +77,7 @@ impl LayerBuilder {
             }
             if !switch {
                 layers.push(
-                    Layer::conv(self.kernels[i].clone(), self.paddings[i].clone(), self.strides[i].clone(), self.activations[i].clone())
+                    Layer::conv(self.kernels[i], self.channels[i], self.paddings[i].clone(), self.strides[i], self.activations[i].clone())
                 );
             } else {
                 let nodes = [self.dense_layers[i - 1], self.dense_layers[i]];
@@ -3,5 +3,11 @@ pub mod layer;
 pub mod activation;
 pub mod conv_params;
 pub mod dense_params;
+pub mod initializer;
 pub mod layer_builder;
-pub mod loss_function;
\ No newline at end of file
+pub mod loss_function;
+pub mod tensor;
+pub mod optimizer;
+pub mod dataset;
+pub mod metrics;
+mod registry;
\ No newline at end of file
@@ -1,27 +1,189 @@
-    use serde_derive::{Deserialize, Serialize};
+    use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+// What register_loss needs from outside the crate
+pub use serde_json::Value;
+pub use crate::registry::Loader;
 
+use crate::{activation::{log_sum_exp, Activation, ActivationFunction}, registry::{self, Registry}};
 
-#[derive(Serialize, Deserialize, PartialEq, Clone)]
+// A loss compares one sample's outputs with its targets
+pub trait Loss {
+    fn function(&self, outputs: &[f64], targets: &[f64]) -> f64;
+    // Gradient w.r.t. the outputs, or w.r.t. the weighted inputs when the loss is fused
+    fn derivative(&self, outputs: &[f64], targets: &[f64]) -> Vec<f64>;
+    // Output activation this loss is fused with, the network then skips that activation's backward pass
+    fn fused_activation(&self) -> Option<ActivationFunction> {
+        None
+    }
+    // Whether fused_derivative can take the gradient through this output activation. Pairs like CEL and
+    // SoftMax have a closed form that stays finite where the gradient w.r.t. the outputs blows up
+    fn fuses_with(&self, output_activation: &ActivationFunction) -> bool {
+        self.fused_activation().as_ref() == Some(output_activation)
+    }
+    // Gradient w.r.t. the weighted inputs of an output activation accepted by fuses_with
+    fn fused_derivative(&self, outputs: &[f64], targets: &[f64]) -> Vec<f64> {
+        self.derivative(outputs, targets)
+    }
+    // Whether this loss makes sense on top of the output layer's activation
+    fn supports(&self, _output_activation: Option<&ActivationFunction>) -> bool {
+        true
+    }
+    // Turns raw outputs into what metrics read, e.g. probabilities for losses that take logits
+    fn predictions(&self, outputs: &[f64]) -> Vec<f64> {
+        outputs.to_vec()
+    }
+    // Checks settings that depend on the number of outputs per sample, e.g. per-class weights
+    fn check_outputs(&self, _outputs: usize) -> Result<(), String> {
+        Ok(())
+    }
+    // Settings such as weights and smoothing go in the state, they are not part of the name
+    fn name(&self) -> &'static str;
+    fn state(&self) -> Value;
+}
+
+static LOSSES: Registry<dyn Loss> = Registry::new();
+
+// Custom losses must be registered under their name() before a model using them is loaded
+pub fn register_loss(name: &str, loader: Loader<dyn Loss>) {
+    LOSSES.register(name, loader);
+}
+
+impl serde::Serialize for Box<dyn Loss> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        registry::serialize_named(self.name(), self.state(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Box<dyn Loss> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = <Value as serde::Deserialize>::deserialize(deserializer)?;
+        // Models saved before losses were trait objects hold a bare LossFunction
+        if state.get("loss_type").is_some() {
+            return serde_json::from_value::<LossFunction>(state).map(|loss| Box::new(loss) as Box<dyn Loss>).map_err(serde::de::Error::custom);
+        }
+        let (name, state) = registry::deserialize_named(state).map_err(serde::de::Error::custom)?;
+        let loss = match name.as_str() {
+            "MSE" | "CEL" | "SoftmaxCrossEntropy" | "NLL" | "CrossEntropyLogits" | "BCE" | "BCEWithLogits" | "MAE" | "Huber" | "LogCosh" | "Focal" =>
+                serde_json::from_value::<LossFunction>(state).map(|loss| Box::new(loss) as Box<dyn Loss>),
+            _ => LOSSES.load(&name, state)
+                .ok_or_else(|| serde::de::Error::custom(format!("Unknown loss \"{}\", register it with register_loss", name)))?,
+        };
+        loss.map_err(serde::de::Error::custom)
+    }
+}
+
+impl<T: Loss + 'static> From<T> for Box<dyn Loss> {
+    fn from(loss: T) -> Self {
+        Box::new(loss)
+    }
+}
+
+impl From<LossType> for Box<dyn Loss> {
+    fn from(loss_type: LossType) -> Self {
+        Box::new(LossFunction::new(loss_type))
+    }
+}
+
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum LossType {
     MSE,
-    CEL
+    CEL,
+    SoftmaxCrossEntropy, //CEL fused with a SoftMax output layer, its gradient is taken w.r.t. the logits
+    NLL, //cross entropy on log-probabilities, pairs with a LogSoftMax output layer
+    CrossEntropyLogits, //cross entropy on raw logits, the softmax happens inside the loss
+    BCE, //binary cross entropy per output, for multi-label sigmoid outputs
+    BCEWithLogits, //BCE on raw logits, the sigmoid happens inside the loss
+    MAE,
+    Huber { delta: f64 }, //squared below delta, linear above it
+    LogCosh,
+    Focal { gamma: f64, alpha: f64 }, //CEL scaled by alpha * (1 - p)^gamma, so confident predictions count less
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum Reduction {
+    #[default]
+    Sum, //added over the outputs of a sample
+    Mean, //averaged over the outputs of a sample
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LossFunction {
-    pub loss_type: LossType
+    pub loss_type: LossType,
+    #[serde(default)]
+    pub label_smoothing: f64, //share of the target mass spread evenly over every class, cross entropy and focal losses only
+    #[serde(default)]
+    pub pos_weights: Option<Vec<f64>>, //per-class weight on the positive term, BCE losses only
+    #[serde(default)]
+    pub reduction: Reduction,
+    #[serde(default)]
+    pub class_weights: Option<Vec<f64>>, //per-class weight on each class's term, classification losses only
 }
 
 
 impl LossFunction {
     pub fn new(loss_type: LossType) -> Self {
         LossFunction {
-            loss_type
+            loss_type,
+            label_smoothing: 0.0,
+            pos_weights: None,
+            reduction: Reduction::Sum,
+            class_weights: None,
         }
     }
 
-    pub fn function(&self, outputs: &Vec<f64>, targets: &Vec<f64>, true_index: usize) -> f64 {
-        match self.loss_type {
+    pub fn set_class_weights(&mut self, class_weights: Vec<f64>) {
+        self.class_weights = Some(class_weights);
+    }
+
+    fn class_weight(&self, class: usize) -> f64 {
+        self.class_weights.as_ref().map_or(1.0, |weights| weights[class])
+    }
+
+    pub fn set_reduction(&mut self, reduction: Reduction) {
+        self.reduction = reduction;
+    }
+
+    fn scale(&self, outputs: usize) -> f64 {
+        match self.reduction {
+            Reduction::Sum => 1.0,
+            Reduction::Mean => 1.0 / outputs as f64,
+        }
+    }
+
+    pub fn set_pos_weights(&mut self, pos_weights: Vec<f64>) {
+        self.pos_weights = Some(pos_weights);
+    }
+
+    fn pos_weight(&self, class: usize) -> f64 {
+        self.pos_weights.as_ref().map_or(1.0, |weights| weights[class])
+    }
+
+    fn check_weights(name: &str, weights: &Option<Vec<f64>>, outputs: usize) -> Result<(), String> {
+        match weights {
+            Some(weights) if weights.len() != outputs => Err(format!("{} {} for {} outputs per sample", weights.len(), name, outputs)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_label_smoothing(&mut self, label_smoothing: f64) {
+        assert!((0.0..1.0).contains(&label_smoothing), "Label smoothing must be in [0, 1)");
+        self.label_smoothing = label_smoothing;
+    }
+
+    // Targets are full distributions (one-hot, soft or multi-hot), smoothing mixes them with a uniform one.
+    // Cross entropy terms are linear in the targets, so class weights are folded in here too
+    fn class_targets(&self, targets: &[f64]) -> Vec<f64> {
+        let uniform = self.label_smoothing / targets.len() as f64;
+        targets.iter().enumerate()
+            .map(|(i, t)| (t * (1.0 - self.label_smoothing) + uniform) * self.class_weight(i))
+            .collect()
+    }
+}
+
+impl Loss for LossFunction {
+    fn function(&self, outputs: &[f64], targets: &[f64]) -> f64 {
+        let cost = match self.loss_type {
             LossType::MSE => 
                 {   
                     let mut cost = 0.0;
@@ -30,15 +192,79 @@ impl LossFunction {
                     }
                     cost
                 },
-            LossType::CEL => 
+            LossType::CEL | LossType::SoftmaxCrossEntropy => 
             {
-                -outputs[true_index].ln()
+                // A probability that underflowed to 0 costs ~708 instead of inf
+                let targets = self.class_targets(targets);
+                -outputs.iter().zip(&targets).map(|(p, t)| t * p.max(f64::MIN_POSITIVE).ln()).sum::<f64>()
             },
-        }
+            LossType::NLL => 
+            {
+                let targets = self.class_targets(targets);
+                -outputs.iter().zip(&targets).map(|(y, t)| t * y).sum::<f64>()
+            },
+            LossType::CrossEntropyLogits => 
+            {
+                let targets = self.class_targets(targets);
+                let log_sum_exp = log_sum_exp(outputs);
+                outputs.iter().zip(&targets).map(|(z, t)| t * (log_sum_exp - z)).sum()
+            },
+            LossType::BCE => 
+            {
+                let mut cost = 0.0;
+                for (i, (p, t)) in outputs.iter().zip(targets).enumerate() {
+                    let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+                    cost -= self.class_weight(i) * (self.pos_weight(i) * t * p.ln() + (1.0 - t) * (1.0 - p).ln());
+                }
+                cost
+            },
+            LossType::BCEWithLogits => 
+            {
+                // -ln(sigmoid(z)) = softplus(-z) and -ln(1 - sigmoid(z)) = z + softplus(-z)
+                let mut cost = 0.0;
+                for (i, (z, t)) in outputs.iter().zip(targets).enumerate() {
+                    let softplus = (-z).max(0.0) + (-z.abs()).exp().ln_1p();
+                    cost += self.class_weight(i) * (self.pos_weight(i) * t * softplus + (1.0 - t) * (z + softplus));
+                }
+                cost
+            },
+            LossType::MAE => 
+            {
+                outputs.iter().zip(targets).map(|(o, t)| (o - t).abs()).sum()
+            },
+            LossType::Huber { delta } => 
+            {
+                outputs.iter().zip(targets).map(|(o, t)| {
+                    let error = (o - t).abs();
+                    if error <= delta {
+                        0.5 * error * error
+                    } else {
+                        delta * (error - 0.5 * delta)
+                    }
+                }).sum()
+            },
+            LossType::LogCosh => 
+            {
+                // ln(cosh(x)) = |x| + ln(1 + e^(-2|x|)) - ln(2), cosh itself overflows for large errors
+                outputs.iter().zip(targets).map(|(o, t)| {
+                    let error = (o - t).abs();
+                    error + (-2.0 * error).exp().ln_1p() - std::f64::consts::LN_2
+                }).sum()
+            },
+            LossType::Focal { gamma, alpha } => 
+            {
+                let targets = self.class_targets(targets);
+                -alpha * outputs.iter().zip(&targets).map(|(p, t)| {
+                    let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+                    t * (1.0 - p).powf(gamma) * p.ln()
+                }).sum::<f64>()
+            },
+        };
+        cost * self.scale(outputs.len())
     }
 
-    pub fn derivative(&self, outputs: &Vec<f64>, targets: &Vec<f64>, true_index: usize) -> Vec<f64> {
-        match self.loss_type {
+    fn derivative(&self, outputs: &[f64], targets: &[f64]) -> Vec<f64> {
+        let gradients = match self.loss_type {
             LossType::MSE => 
                 {   
                     let mut loss_gradient: Vec<f64> = vec![0.0; targets.len()];
@@ -49,10 +275,274 @@ impl LossFunction {
                 },
             LossType::CEL => 
                 {
-                    let mut gradients = outputs.clone();
-                    gradients[true_index] -= 1.0;
+                    let targets = self.class_targets(targets);
+                    outputs.iter().zip(&targets).map(|(p, t)| -t / p.max(f64::MIN_POSITIVE)).collect()
+                },
+            LossType::SoftmaxCrossEntropy => return self.fused_derivative(outputs, targets),
+            LossType::NLL => 
+                {
+                    self.class_targets(targets).iter().map(|t| -t).collect()
+                },
+            LossType::CrossEntropyLogits => 
+                {
+                    let targets = self.class_targets(targets);
+                    let mass: f64 = targets.iter().sum();
+                    let probabilities = Activation::new(ActivationFunction::SoftMax).function(outputs);
+                    probabilities.iter().zip(&targets).map(|(p, t)| p * mass - t).collect()
+                },
+            LossType::BCE => 
+                {
+                    let mut gradients = vec![0.0; outputs.len()];
+                    for (i, (p, t)) in outputs.iter().zip(targets).enumerate() {
+                        let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+                        gradients[i] = self.class_weight(i) * (-self.pos_weight(i) * t / p + (1.0 - t) / (1.0 - p));
+                    }
+                    gradients
+                },
+            LossType::BCEWithLogits => 
+                {
+                    let sigmoid = Activation::new(ActivationFunction::Sigmoid).function(outputs);
+                    let mut gradients = vec![0.0; outputs.len()];
+                    for (i, (p, t)) in sigmoid.iter().zip(targets).enumerate() {
+                        let weight = self.pos_weight(i);
+                        gradients[i] = self.class_weight(i) * (p * (weight * t + 1.0 - t) - weight * t);
+                    }
+                    gradients
+                },
+            LossType::MAE => 
+                {
+                    outputs.iter().zip(targets).map(|(o, t)| {
+                        if o == t {
+                            0.0
+                        } else {
+                            (o - t).signum()
+                        }
+                    }).collect()
+                },
+            LossType::Huber { delta } => 
+                {
+                    outputs.iter().zip(targets).map(|(o, t)| (o - t).clamp(-delta, delta)).collect()
+                },
+            LossType::LogCosh => 
+                {
+                    outputs.iter().zip(targets).map(|(o, t)| (o - t).tanh()).collect()
+                },
+            LossType::Focal { gamma, alpha } => 
+                {
+                    let targets = self.class_targets(targets);
+                    outputs.iter().zip(&targets).map(|(p, t)| {
+                        let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+                        let modulation = (1.0 - p).powf(gamma);
+                        let modulation_gradient = if gamma == 0.0 {
+                            0.0
+                        } else {
+                            gamma * (1.0 - p).powf(gamma - 1.0)
+                        };
+                        -alpha * t * (modulation / p - modulation_gradient * p.ln())
+                    }).collect()
+                },
+        };
+        let scale = self.scale(outputs.len());
+        gradients.into_iter().map(|gradient| gradient * scale).collect()
+    }
+
+    fn fused_activation(&self) -> Option<ActivationFunction> {
+        match self.loss_type {
+            LossType::SoftmaxCrossEntropy => Some(ActivationFunction::SoftMax),
+            _ => None,
+        }
+    }
+
+    fn fuses_with(&self, output_activation: &ActivationFunction) -> bool {
+        matches!((&self.loss_type, output_activation),
+            (LossType::CEL | LossType::SoftmaxCrossEntropy | LossType::Focal { .. }, ActivationFunction::SoftMax)
+            | (LossType::BCE, ActivationFunction::Sigmoid))
+    }
+
+    fn fused_derivative(&self, outputs: &[f64], targets: &[f64]) -> Vec<f64> {
+        let gradients: Vec<f64> = match self.loss_type {
+            LossType::CEL | LossType::SoftmaxCrossEntropy => 
+                {
+                    // Targets that don't sum to 1 scale the softmax term by their total mass
+                    let targets = self.class_targets(targets);
+                    let mass: f64 = targets.iter().sum();
+                    outputs.iter().zip(&targets).map(|(p, t)| p * mass - t).collect()
+                },
+            LossType::Focal { gamma, alpha } => 
+                {
+                    // Softmax backward is p_j * (g_j - sum(g_i * p_i)), every g_i * p_i stays finite as p_i goes to 0
+                    let targets = self.class_targets(targets);
+                    let weighted: Vec<f64> = outputs.iter().zip(&targets).map(|(p, t)| {
+                        let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+                        let modulation = (1.0 - p).powf(gamma);
+                        let modulation_gradient = if gamma == 0.0 {
+                            0.0
+                        } else {
+                            gamma * (1.0 - p).powf(gamma - 1.0)
+                        };
+                        -alpha * t * (modulation - modulation_gradient * p * p.ln())
+                    }).collect();
+                    let total: f64 = weighted.iter().sum();
+                    outputs.iter().zip(&weighted).map(|(p, q)| q - p * total).collect()
+                },
+            LossType::BCE => 
+                {
+                    let mut gradients = vec![0.0; outputs.len()];
+                    for (i, (p, t)) in outputs.iter().zip(targets).enumerate() {
+                        let weight = self.pos_weight(i);
+                        gradients[i] = self.class_weight(i) * (p * (weight * t + 1.0 - t) - weight * t);
+                    }
                     gradients
                 },
+            _ => return self.derivative(outputs, targets),
+        };
+        let scale = self.scale(outputs.len());
+        gradients.into_iter().map(|gradient| gradient * scale).collect()
+    }
+
+    fn supports(&self, output_activation: Option<&ActivationFunction>) -> bool {
+        let log_probabilities = output_activation == Some(&ActivationFunction::LogSoftMax);
+        match self.loss_type {
+            LossType::MSE | LossType::MAE | LossType::Huber { .. } | LossType::LogCosh => true,
+            LossType::CEL | LossType::Focal { .. } => !log_probabilities,
+            LossType::SoftmaxCrossEntropy => output_activation == Some(&ActivationFunction::SoftMax),
+            LossType::NLL => log_probabilities,
+            LossType::CrossEntropyLogits => !log_probabilities && output_activation != Some(&ActivationFunction::SoftMax),
+            LossType::BCE => matches!(output_activation, None | Some(ActivationFunction::Sigmoid) | Some(ActivationFunction::HardSigmoid)),
+            LossType::BCEWithLogits => !matches!(output_activation, Some(ActivationFunction::Sigmoid) | Some(ActivationFunction::SoftMax) | Some(ActivationFunction::LogSoftMax)),
+        }
+    }
+
+    fn predictions(&self, outputs: &[f64]) -> Vec<f64> {
+        match self.loss_type {
+            LossType::NLL => outputs.iter().map(|y| y.exp()).collect(),
+            LossType::CrossEntropyLogits => Activation::new(ActivationFunction::SoftMax).function(outputs),
+            LossType::BCEWithLogits => Activation::new(ActivationFunction::Sigmoid).function(outputs),
+            _ => outputs.to_vec(),
+        }
+    }
+
+    fn check_outputs(&self, outputs: usize) -> Result<(), String> {
+        Self::check_weights("pos weights", &self.pos_weights, outputs)?;
+        Self::check_weights("class weights", &self.class_weights, outputs)
+    }
+
+    fn name(&self) -> &'static str {
+        match self.loss_type {
+            LossType::MSE => "MSE",
+            LossType::CEL => "CEL",
+            LossType::SoftmaxCrossEntropy => "SoftmaxCrossEntropy",
+            LossType::NLL => "NLL",
+            LossType::CrossEntropyLogits => "CrossEntropyLogits",
+            LossType::BCE => "BCE",
+            LossType::BCEWithLogits => "BCEWithLogits",
+            LossType::MAE => "MAE",
+            LossType::Huber { .. } => "Huber",
+            LossType::LogCosh => "LogCosh",
+            LossType::Focal { .. } => "Focal",
+        }
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGITS: [f64; 3] = [0.4, -1.1, 2.3];
+
+    fn numeric_gradient(f: impl Fn(&[f64]) -> f64, at: &[f64]) -> Vec<f64> {
+        let eps = 1e-6;
+        (0..at.len()).map(|i| {
+            let mut plus = at.to_vec();
+            let mut minus = at.to_vec();
+            plus[i] += eps;
+            minus[i] -= eps;
+            (f(&plus) - f(&minus)) / (2.0 * eps)
+        }).collect()
+    }
+
+    fn assert_close(loss: &LossFunction, analytic: &[f64], numeric: &[f64]) {
+        for (a, n) in analytic.iter().zip(numeric) {
+            assert!((a - n).abs() < 1e-5, "{:?}: {:?} vs {:?}", loss.loss_type, analytic, numeric);
         }
     }
-}
\ No newline at end of file
+
+    // fused_derivative against central differences of the loss taken through the activation
+    fn check_fused(loss: &LossFunction, function: ActivationFunction, targets: &[f64]) {
+        assert!(loss.fuses_with(&function));
+        let activation = Activation::new(function);
+        let analytic = loss.fused_derivative(&activation.function(&LOGITS), targets);
+        let numeric = numeric_gradient(|z| loss.function(&activation.function(z), targets), &LOGITS);
+        assert_close(loss, &analytic, &numeric);
+    }
+
+    fn check_derivative(loss: &LossFunction, outputs: &[f64], targets: &[f64]) {
+        let numeric = numeric_gradient(|o| loss.function(o, targets), outputs);
+        assert_close(loss, &loss.derivative(outputs, targets), &numeric);
+    }
+
+    #[test]
+    fn cross_entropy_fuses_with_softmax() {
+        for loss_type in [LossType::CEL, LossType::SoftmaxCrossEntropy] {
+            check_fused(&LossFunction::new(loss_type.clone()), ActivationFunction::SoftMax, &[0.0, 0.0, 1.0]);
+            check_fused(&LossFunction::new(loss_type), ActivationFunction::SoftMax, &[0.2, 0.8, 0.0]);
+        }
+    }
+
+    #[test]
+    fn cross_entropy_derivative() {
+        check_derivative(&LossFunction::new(LossType::CEL), &[0.2, 0.3, 0.5], &[0.0, 1.0, 0.0]);
+        check_derivative(&LossFunction::new(LossType::MSE), &[0.2, -0.3, 0.5], &[0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn log_probability_and_logit_losses_derivative() {
+        let log_probabilities = Activation::new(ActivationFunction::LogSoftMax).function(&LOGITS);
+        check_derivative(&LossFunction::new(LossType::NLL), &log_probabilities, &[0.0, 1.0, 0.0]);
+        check_derivative(&LossFunction::new(LossType::CrossEntropyLogits), &LOGITS, &[0.0, 1.0, 0.0]);
+        check_derivative(&LossFunction::new(LossType::CrossEntropyLogits), &LOGITS, &[0.5, 0.25, 0.25]);
+    }
+    #[test]
+    fn binary_cross_entropy_derivative() {
+        let mut loss = LossFunction::new(LossType::BCE);
+        loss.set_pos_weights(vec![2.0, 0.5, 1.0]);
+        check_derivative(&loss, &[0.2, 0.7, 0.4], &[1.0, 0.0, 1.0]);
+        check_fused(&loss, ActivationFunction::Sigmoid, &[1.0, 0.0, 1.0]);
+        let mut logits = LossFunction::new(LossType::BCEWithLogits);
+        logits.set_pos_weights(vec![2.0, 0.5, 1.0]);
+        check_derivative(&logits, &LOGITS, &[1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn pos_weights_must_match_the_outputs() {
+        let mut loss = LossFunction::new(LossType::BCE);
+        loss.set_pos_weights(vec![2.0, 1.0]);
+        assert!(loss.check_outputs(2).is_ok());
+        assert!(loss.check_outputs(3).is_err());
+    }
+    #[test]
+    fn focal_gradients() {
+        let mut loss = LossFunction::new(LossType::Focal { gamma: 2.0, alpha: 0.25 });
+        check_derivative(&loss, &[0.2, 0.3, 0.5], &[0.0, 1.0, 0.0]);
+        check_fused(&loss, ActivationFunction::SoftMax, &[0.0, 1.0, 0.0]);
+        loss.set_class_weights(vec![0.5, 2.0, 1.0]);
+        loss.set_label_smoothing(0.1);
+        check_derivative(&loss, &[0.2, 0.3, 0.5], &[0.0, 1.0, 0.0]);
+        check_fused(&loss, ActivationFunction::SoftMax, &[0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn weighted_cross_entropy_fuses_with_softmax() {
+        let mut loss = LossFunction::new(LossType::CEL);
+        loss.set_class_weights(vec![0.5, 2.0, 1.0]);
+        loss.set_label_smoothing(0.1);
+        check_derivative(&loss, &[0.2, 0.3, 0.5], &[0.0, 1.0, 0.0]);
+        check_fused(&loss, ActivationFunction::SoftMax, &[0.0, 1.0, 0.0]);
+        assert!(loss.check_outputs(3).is_ok());
+        assert!(loss.check_outputs(4).is_err());
+    }
+}
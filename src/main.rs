@@ -1,7 +1,7 @@
 use std::time;
 use image::*;
 
-use sprout::{conv_params::PaddingType::*, activation::ActivationFunction::*, loss_function::LossType::*, conv_params::PaddingType, layer::{Layer, LayerType::*}, network::Network};
+use sprout::{conv_params::PaddingType::*, activation::ActivationFunction::*, loss_function::LossType::*, layer::Layer, network::Network, tensor::Tensor};
 
 fn main() {
     let time = time::Instant::now();
@@ -16,9 +16,9 @@ fn main() {
 pub fn conv_model() {
 
     let layers = vec![
-        Layer::conv(3, Valid, 1, ReLU),
+        Layer::conv(3, 2, Valid, 1, ReLU),
         Layer::pool(2, 2),
-        Layer::dense([4, 3], Sigmoid),
+        Layer::dense([8, 3], Sigmoid),
     ];
 
     let data = vec![
@@ -62,8 +62,8 @@ pub fn conv_model() {
 
     let mut nn = Network::new(layers, 0.01, 3, MSE);
     nn.conv_train(data.clone(), 10000);
-    for i in 0..data.len() {
-        let inputs = data[i].0.clone();
+    for (i, sample) in data.iter().enumerate() {
+        let inputs = sample.0.clone();
         println!("Output {}: {:?}", i + 1, nn.conv_forward(inputs));
     }
 }
@@ -73,15 +73,15 @@ fn conv_check() {
 
     let mut pixels = vec![vec![0.0; img.width() as usize]; img.height() as usize];
 
-    for y in 0..pixels.len() {
-        for x in 0..pixels[y].len() {
+    for (y, row) in pixels.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
             let pix = img.get_pixel(x as u32, y as u32).0;
             let int = ((pix[0] / 3) + (pix[1] / 3) + (pix[2] / 3)) as f64 / 255.0;
-            pixels[y][x] = int;
+            *pixel = int;
         }
     }
 
-    let data = vec![(vec![pixels], vec![0.0, 1.0, 0.0])];
+    let data = [(vec![pixels], vec![0.0, 1.0, 0.0])];
 
     let layers = vec![
         Layer::pool(2, 2),
@@ -90,16 +90,16 @@ fn conv_check() {
 
     let mut nn = Network::new(layers, 0.1, 1, MSE);
 
-    let pooled = nn.layers[0].pool_forward(data[0].0.clone());
+    let pooled = nn.layers[0].forward(Tensor::stack(&[Tensor::from(data[0].0.clone())]));
 
-    let new_img = ImageBuffer::from_fn(pooled[0][0].len() as u32, 
-        pooled[0].len() as u32, 
+    let new_img = ImageBuffer::from_fn(pooled.shape()[3] as u32, 
+        pooled.shape()[2] as u32, 
         |x, y| {
-            let int = (pooled[0][y as usize][x as usize] * 255.0) as u8;
+            let int = (pooled[[0, 0, y as usize, x as usize]] * 255.0) as u8;
             Rgb([int, int, int])
         }
     );
-    new_img.save("pooled.png");
+    new_img.save("pooled.png").unwrap();
 
 }
 
@@ -123,8 +123,8 @@ pub fn xor_mode(epochs: usize) {
     nn.dense_train(data.clone(), epochs);
     // nn.load_model("test1");
 
-    for i in 0..data.len() {
-        println!("Input: {:?} // Output: {:?} // Target: {:?}",data[i][0].clone(), nn.dense_forward(data[i][0].clone()), data[i][1].clone());
+    for sample in &data {
+        println!("Input: {:?} // Output: {:?} // Target: {:?}", sample[0], nn.dense_forward(sample[0].clone()), sample[1]);
     }
 }
 
@@ -135,10 +135,9 @@ pub fn digit_model(mut nn: Network, epochs: usize) {
 
     for y in 0..img.dimensions().1 {
         for x in 0..img.dimensions().0 {
-            let intensity;
             let pixel = img.get_pixel(x, y).0;
-            intensity = (pixel[0] / 3) + (pixel[1] / 3) + (pixel[2] / 3);
-            data.push([vec![x as f64, y as f64], vec![(intensity as f64 / 255 as f64)]]);
+            let intensity = (pixel[0] / 3) + (pixel[1] / 3) + (pixel[2] / 3);
+            data.push([vec![x as f64, y as f64], vec![(intensity as f64 / 255.0)]]);
         }
     }
 
@@ -174,5 +173,5 @@ pub fn digit_model(mut nn: Network, epochs: usize) {
         // println!();
     }
 
-    let _  = new_image.save("Output.png").unwrap();
+    new_image.save("Output.png").unwrap();
 }
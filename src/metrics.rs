@@ -0,0 +1,56 @@
+use crate::tensor::Tensor;
+
+// Scores for multi-label outputs, a label counts as predicted once its probability reaches the threshold
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MultiLabelMetrics {
+    pub accuracy: f64, //share of individual labels predicted correctly
+    pub exact_match: f64, //share of samples with every label correct
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+// Precision, recall and F1 are micro averaged, every label of every sample counts once
+pub fn multi_label_metrics(probabilities: &[Tensor], targets: &[Tensor], threshold: f64) -> MultiLabelMetrics {
+    assert_eq!(probabilities.len(), targets.len(), "Every prediction needs a target");
+    let mut true_positives = 0.0;
+    let mut false_positives = 0.0;
+    let mut false_negatives = 0.0;
+    let mut correct = 0.0;
+    let mut labels = 0.0;
+    let mut exact = 0.0;
+
+    for (prediction, target) in probabilities.iter().zip(targets) {
+        let mut all_correct = true;
+        for (p, t) in prediction.data().iter().zip(target.data()) {
+            let predicted = *p >= threshold;
+            let actual = *t >= 0.5;
+            match (predicted, actual) {
+                (true, true) => true_positives += 1.0,
+                (true, false) => false_positives += 1.0,
+                (false, true) => false_negatives += 1.0,
+                (false, false) => {},
+            }
+            if predicted == actual {
+                correct += 1.0;
+            } else {
+                all_correct = false;
+            }
+            labels += 1.0;
+        }
+        if all_correct {
+            exact += 1.0;
+        }
+    }
+
+    let ratio = |a: f64, b: f64| if b > 0.0 { a / b } else { 0.0 };
+    let precision = ratio(true_positives, true_positives + false_positives);
+    let recall = ratio(true_positives, true_positives + false_negatives);
+    MultiLabelMetrics {
+        accuracy: ratio(correct, labels),
+        exact_match: ratio(exact, probabilities.len() as f64),
+        precision,
+        recall,
+        f1: ratio(2.0 * precision * recall, precision + recall),
+    }
+}
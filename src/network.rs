@@ -1,8 +1,8 @@
-use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use serde_derive::{Serialize, Deserialize};
 
-use crate::{layer::{Layer, LayerType}, loss_function::{LossFunction, LossType}};
-use std::{fs::File, io::{Read, Write}};
+use crate::{activation::ActivationFunction, dataset::Dataset, layer::{Conv, Dense, LayerImpl, LayerType, Pool}, loss_function::Loss, metrics::{self, MultiLabelMetrics}, optimizer::{Optimizer, SGD}, tensor::Tensor};
+use std::{fmt, fs::File, io::{Read, Write}};
 
 #[derive(Serialize, Deserialize, PartialEq)]
 pub enum NetworkType {
@@ -10,23 +10,68 @@ pub enum NetworkType {
     CNN
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkError {
+    UnsupportedCombination(String),
+    ShapeMismatch(String),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::UnsupportedCombination(message) => write!(f, "Unsupported combination: {}", message),
+            NetworkError::ShapeMismatch(message) => write!(f, "Shape mismatch: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+#[derive(Debug, Clone)]
+pub struct TrainConfig {
+    pub epochs: usize,
+    pub batch_size: Option<usize>, //None uses the network's batch size
+    pub shuffle: bool,
+}
+
+impl TrainConfig {
+    pub fn new(epochs: usize) -> Self {
+        TrainConfig {
+            epochs,
+            batch_size: None,
+            shuffle: true,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Network {
-    pub layers: Vec<Layer>,
-    pub learning_rate: f64,
+    pub layers: Vec<Box<dyn LayerImpl>>,
+    pub learning_rate: f64, //handed to the optimizer on every step, so schedules can change it between fits
     pub batch_size: usize,
     pub cost: f64,
     pub print_progress: bool,
     pub network_type: NetworkType,
-    pub loss_function: LossFunction,
+    pub loss_function: Box<dyn Loss>,
     pub grad_threshold: f64,
+    #[serde(default = "default_optimizer")]
+    pub optimizer: Box<dyn Optimizer>,
+    #[serde(skip, default = "StdRng::from_entropy")]
+    pub rng: StdRng, //shuffling, and the source of every layer's seed
+    #[serde(skip)]
+    pub training: bool,
+}
+
+// Models saved before optimizers existed trained with plain SGD, step hands it the saved learning rate
+fn default_optimizer() -> Box<dyn Optimizer> {
+    Box::new(SGD::new(0.0))
 }
 
 impl Network {
-    pub fn new(layers: Vec<Layer>, learning_rate: f64, batch_size: usize, loss_type: LossType) -> Self {
+    pub fn new(layers: Vec<Box<dyn LayerImpl>>, learning_rate: f64, batch_size: usize, loss: impl Into<Box<dyn Loss>>) -> Self {
         let mut network_type = NetworkType::FCN;
-        for i in 0..layers.len() {
-            if layers[i].layer_type == LayerType::Convolutional || layers[i].layer_type == LayerType::Pooling {
+        for layer in &layers {
+            if matches!(layer.layer_type(), LayerType::Convolutional | LayerType::Pooling | LayerType::Upsampling) {
                 network_type = NetworkType::CNN;
             }
         }
@@ -37,8 +82,11 @@ impl Network {
             cost: 0.0,
             print_progress: false,
             network_type,
-            loss_function: LossFunction::new(loss_type),
+            loss_function: loss.into(),
             grad_threshold: 0.2,
+            optimizer: Box::new(SGD::new(learning_rate)),
+            rng: StdRng::from_entropy(),
+            training: false,
         }
     }
 
@@ -46,317 +94,364 @@ impl Network {
         self.print_progress = value
     }
 
-    pub fn dense_forward(&mut self, inputs: Vec<f64>) -> Vec<f64> {
-        let mut current: Vec<f64> = inputs; 
-        for i in 0..self.layers.len() {
-            current = self.layers[i].dense_forward(current);
+    pub fn set_loss(&mut self, loss: impl Into<Box<dyn Loss>>) {
+        self.loss_function = loss.into();
+    }
+
+    pub fn set_optimizer(&mut self, optimizer: impl Optimizer + 'static) {
+        self.learning_rate = optimizer.learning_rate();
+        self.optimizer = Box::new(optimizer);
+    }
+
+    // Seeds shuffling, dropout and weights drawn from now on. Weights already in place are kept,
+    // so a loaded model can be seeded to resume training, reinit draws them again
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+        for layer in self.layers.iter_mut() {
+            layer.seed(self.rng.gen());
+        }
+    }
+
+    // Seeds the network and re-initializes every layer from it, the same seed and data train to the same weights
+    pub fn reinit(&mut self, seed: u64) {
+        self.set_seed(seed);
+        self.optimizer.reset();
+        for layer in self.layers.iter_mut() {
+            layer.reinit();
+        }
+    }
+
+    // Runs a whole batch through the network, the first axis of `inputs` is the sample
+    pub fn forward(&mut self, inputs: Tensor) -> Tensor {
+        let mut current = inputs;
+        for layer in self.layers.iter_mut() {
+            current = layer.forward(current);
         }
         current
     }
 
-    pub fn conv_forward(&mut self, inputs: Vec<Vec<Vec<f64>>>) -> Vec<f64> {
-        let mut conv_current = inputs; 
-        let mut dense_current = vec![]; 
-        for i in 0..self.layers.len() {
-            match self.layers[i].layer_type {
-                LayerType::Dense => 
-                    {
-                        if i != 0 {
-                            if self.layers[i - 1].layer_type != LayerType::Dense {
-                                dense_current = Self::flatten(&conv_current);
-                            }
-                            dense_current = self.layers[i].dense_forward(dense_current);
-                        } 
-                    },
-                LayerType::Convolutional => 
-                    {
-                        conv_current = self.layers[i].conv_forward(conv_current);
-                    },
-                LayerType::Pooling => 
-                    {
-                        conv_current = self.layers[i].pool_forward(conv_current);
-                    },
+    // Backpropagates the loss gradient of the last forward batch, filling every layer's gradients.
+    // With a fused loss the gradient is already w.r.t. the last layer's weighted inputs
+    pub fn backward(&mut self, loss_gradient: Tensor) {
+        let fused = self.fused();
+        let last = self.layers.len() - 1;
+        let mut delta = loss_gradient;
+        for (i, layer) in self.layers.iter_mut().enumerate().rev() {
+            delta = if fused && i == last {
+                layer.backward_preactivation(delta)
+            } else {
+                layer.backward(delta)
+            };
+        }
+    }
+
+    // Whether loss gradients are taken w.r.t. the last layer's weighted inputs, see Loss::fuses_with.
+    // Conv layers take a softmax per row while the fused gradients assume one per sample, so a conv
+    // softmax keeps its Jacobian (validate rejects losses that can only run fused)
+    pub fn fused(&self) -> bool {
+        let Some(layer) = self.layers.last() else {
+            return false;
+        };
+        layer.activation().is_some_and(|activation| {
+            self.loss_function.fuses_with(&activation.function) && !Self::row_softmax(layer.as_ref(), &activation.function)
+        })
+    }
+
+    fn row_softmax(layer: &dyn LayerImpl, function: &ActivationFunction) -> bool {
+        layer.layer_type() == LayerType::Convolutional && *function == ActivationFunction::SoftMax
+    }
+
+    // Checks that the loss can be used with the output layer
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        let output_activation = self.layers.last().and_then(|layer| layer.activation()).map(|activation| &activation.function);
+        if !self.loss_function.supports(output_activation) {
+            return Err(NetworkError::UnsupportedCombination(format!(
+                "{} loss cannot be used with a {:?} output layer", self.loss_function.name(), output_activation
+            )));
+        }
+        if let (Some(layer), Some(function)) = (self.layers.last(), self.loss_function.fused_activation()) {
+            if Self::row_softmax(layer.as_ref(), &function) {
+                return Err(NetworkError::UnsupportedCombination(format!(
+                    "{} loss needs one softmax per sample, a conv output layer takes one per row", self.loss_function.name()
+                )));
             }
         }
-        dense_current
+        Ok(())
     }
 
-    pub fn flatten(inputs: &Vec<Vec<Vec<f64>>>) -> Vec<f64> {
-        let flat = inputs.iter()
-            .flat_map(|row| row.iter())
-            .flat_map(|col| col.iter())
-            .cloned() // Cloned to avoid borrowing issues
-            .collect();
-        flat
-    }
-
-    pub fn reshape(input: Vec<f64>, channels: usize, rows: usize, cols: usize) -> Vec<Vec<Vec<f64>>> {
-        let mut output = vec![vec![vec![0.0; cols]; rows]; channels];
-        
-        for i in 0..channels {
-            let mut index = 0;
-            for j in 0..rows {
-                for k in 0..cols {
-                    output[i][j][k] = input[index].clone();
-                    index += 1;
+    pub fn dense_forward(&mut self, inputs: impl Into<Tensor>) -> Tensor {
+        self.forward_sample(inputs.into())
+    }
+
+    pub fn conv_forward(&mut self, inputs: impl Into<Tensor>) -> Tensor {
+        self.forward_sample(inputs.into())
+    }
+
+    // Single predictions always run in inference mode, the previous mode is restored afterwards
+    fn forward_sample(&mut self, input: Tensor) -> Tensor {
+        let training = self.training;
+        self.eval_mode();
+        let mut shape = vec![1];
+        shape.extend(input.shape());
+        let output = self.forward(input.reshape(&shape));
+        self.set_training(training);
+        let shape = output.shape()[1..].to_vec();
+        output.reshape(&shape)
+    }
+
+    pub fn flatten(inputs: impl Into<Tensor>) -> Tensor {
+        inputs.into().flatten()
+    }
+
+    pub fn reshape(input: impl Into<Tensor>, channels: usize, rows: usize, cols: usize) -> Tensor {
+        input.into().reshape(&[channels, rows, cols])
+    }
+
+    pub fn conv_backward(&mut self, loss_gradient: Tensor) {
+        self.backward(loss_gradient);
+    }
+
+    pub fn dense_backward(&mut self, loss_gradient: Tensor) {
+        self.backward(loss_gradient);
+    }
+
+    // Backward passes only accumulate gradients, step applies them through the optimizer
+    // and clears them. Parameters are numbered in layer order to get their optimizer ids
+    pub fn step(&mut self) {
+        self.optimizer.set_learning_rate(self.learning_rate);
+        let mut id = 0;
+        for layer in self.layers.iter_mut() {
+            for (params, grads) in layer.parameters() {
+                if !params.is_empty() {
+                    self.optimizer.update(id, params, grads);
                 }
+                grads.fill(0.0);
+                id += 1;
             }
         }
-    
-        output
-    }
-
-    pub fn conv_backward(&mut self, loss_gradient: Vec<f64>) {
-        let mut delta_output = loss_gradient.clone();
-        let mut conv_delta = vec![];
-
-        for i in (0..self.layers.len()).rev() {
-            match self.layers[i].layer_type {
-                LayerType::Dense => 
-                    {
-                        let layer = &mut self.layers[i];
-                        delta_output = layer.dense_backward(delta_output.clone(), self.learning_rate);  
-                        if i - 1 >= 0 as usize {
-                            if self.layers[i - 1].layer_type != LayerType::Dense {
-                                let params = self.layers[i - 1].conv_params.as_ref().unwrap();
-                                conv_delta = Self::reshape(delta_output.clone(), 
-                                    params.outputs.len(), 
-                                    params.outputs[0].len(), 
-                                    params.outputs[0][0].len()
-                                );
-                            }
-                        } 
-                    },
-                LayerType::Convolutional => 
-                    {
-                        let layer = &mut self.layers[i];
-                        conv_delta = layer.conv_backward(conv_delta.clone(), self.learning_rate);
-                    },
-                LayerType::Pooling => 
-                    {
-                        let layer = &mut self.layers[i];
-                        conv_delta = layer.pool_backward(conv_delta.clone());
-                    },
-            }
+    }
+
+    pub fn zero_grad(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.zero_grad();
         }
     }
 
-    pub fn dense_backward(&mut self, loss_gradient: Vec<f64>) {
-        let mut delta_output = loss_gradient.clone();
+    // Dropout and batch statistics are only used in train mode, which fit switches on by itself
+    pub fn train_mode(&mut self) {
+        self.set_training(true);
+    }
+
+    pub fn eval_mode(&mut self) {
+        self.set_training(false);
+    }
 
-        for i in (0..self.layers.len()).rev() {
-            let layer = &mut self.layers[i];
-            delta_output = layer.dense_backward(delta_output.clone(), self.learning_rate);
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        for layer in self.layers.iter_mut() {
+            layer.set_training(training);
         }
     }
 
-    pub fn conv_train(&mut self, mut data: Vec<(Vec<Vec<Vec<f64>>>, Vec<f64>)>, epochs: usize) {
+    // Trains any layer stack, samples are shaped for the network type before batching
+    pub fn fit(&mut self, dataset: impl Into<Dataset>, config: TrainConfig) -> Result<(), NetworkError> {
+        self.validate()?;
+        let mut data: Vec<(Tensor, Tensor)> = dataset.into().samples.into_iter()
+            .map(|(input, target)| (self.prepare_input(input), target))
+            .collect();
+        if let Some((_, target)) = data.first() {
+            self.loss_function.check_outputs(target.len()).map_err(NetworkError::ShapeMismatch)?;
+        }
+        let training = self.training;
+        self.train_mode();
+        let batch_size = config.batch_size.unwrap_or(self.batch_size).max(1);
         let samples = data.len() as f64;
-        for i in 0..epochs {
+
+        for i in 0..config.epochs {
             if i % 1000 == 0 && self.print_progress {
-                println!("Progress: {}%", 100.0 * (i as f64 / epochs as f64));
+                println!("Progress: {}%", 100.0 * (i as f64 / config.epochs as f64));
             }
 
-            self.cost = 0.0; // Reset cost
-            
-            Self::shuffle_tensor(&mut data);
-            
-            let batches = (samples / self.batch_size as f64).ceil() as usize;
-    
-            for batch in 0..batches { //each batch
-                let mut loss_gradient: Vec<f64> = vec![0.0; data[0].1.len()]; //output nodes
-                let mut sample_max = self.batch_size;
-                let current_sample = batch * self.batch_size;
-                if  current_sample + self.batch_size >= samples as usize && self.batch_size != 1 {
-                    sample_max = samples as usize - current_sample;
-                }
-                for s in 0..sample_max { //each sample
-                    let sample = data[current_sample + s].clone();
-                    let output = self.conv_forward(sample.0.clone());
-                    let target = &sample.1;
-                    let mut true_index = 0;
-                    for i in 0..target.len() {
-                        if target[i] == 1.0 {
-                            true_index = i;
-                            break;
-                        }
-                    }
-                    let mut cost = self.loss_function.function(&output, &target, true_index);
-                    if cost.is_nan() || cost.is_infinite() {
-                        cost = 0.0;
-                    }
-
-                    self.cost += cost;
-        
-                    let sample_loss = self.loss_function.derivative(&output, target, true_index);
-
-                    for i in 0..loss_gradient.len() { //Sum Gradients
-                        loss_gradient[i] += sample_loss[i];
-                    }
-
-                    let l2_norm = loss_gradient.iter().map(|x| x.powf(2.0)).sum::<f64>().sqrt();
-
-                    if l2_norm > self.grad_threshold {
-                        let scale = self.grad_threshold / l2_norm;
-                        for i in 0..loss_gradient.len() {
-                            loss_gradient[i] *= scale;
-                        }
-                    }
-                    
-                }
-                // for i in 0..loss_gradient.len() {
-                //     loss_gradient[i] /= self.batch_size as f64;
-                // }
+            if config.shuffle {
+                self.shuffle_tensor(&mut data);
+            }
+            self.cost = 0.0; // Reset cost for each epoch
 
-                self.conv_backward(loss_gradient);
-                
+            for batch in data.chunks(batch_size) { //each batch
+                let inputs: Vec<&Tensor> = batch.iter().map(|sample| &sample.0).collect();
+                let targets: Vec<&Tensor> = batch.iter().map(|sample| &sample.1).collect();
+                self.cost += self.train_batch(&inputs, &targets).iter().sum::<f64>();
             }
             self.cost /= samples; // Compute average cost per sample
         }
-    
+        self.set_training(training);
+
         if self.print_progress {
             println!("Training Complete");
         }
+        Ok(())
     }
 
-    pub fn dense_train(&mut self, mut data: Vec<[Vec<f64>; 2]>, epochs: usize) {
-        let samples = data.len() as f64;
-    
-        for i in 0..epochs {
-            if i % 1000 == 0 && self.print_progress {
-                println!("Progress: {}%", 100.0 * (i as f64 / epochs as f64));
-            }
-            
-            Self::shuffle_vector(&mut data);
-            self.cost = 0.0; // Reset cost for each epoch
+    pub fn predict(&mut self, input: impl Into<Tensor>) -> Tensor {
+        let input = self.prepare_input(input.into());
+        self.forward_sample(input)
+    }
 
-            let batches = (samples / self.batch_size as f64).ceil() as usize;
-    
-            for batch in 0..batches { //each batch
-                let mut loss_gradient: Vec<f64> = vec![0.0; data[0][1].len()]; //output nodes
-                let mut sample_max = self.batch_size;
-                let current_sample = batch * self.batch_size;
-                if  current_sample + self.batch_size >= samples as usize  && self.batch_size != 1 {
-                    sample_max = samples as usize - current_sample;
-                }
-                for s in 0..sample_max { //each sample
-                    let sample = data[batch * self.batch_size + s].clone();
-                    let output = self.dense_forward(sample[0].clone());
-                    let target = &sample[1];
-                    let mut true_index = 0;
-                    for i in 0..target.len() {
-                        if target[i] == 1.0 {
-                            true_index = i;
-                            break;
-                        }
-                    }
-                    self.cost += self.loss_function.function(&output, &target, true_index);
-        
-                    let sample_loss = self.loss_function.derivative(&output, target, true_index);
-
-                    for i in 0..loss_gradient.len() { //Sum Gradients
-                        loss_gradient[i] += sample_loss[i];
-                    }
-
-                    let l2_norm = loss_gradient.iter().map(|x| x.powf(2.0)).sum::<f64>().sqrt();
-
-                    if l2_norm > self.grad_threshold {
-                        let scale = self.grad_threshold / l2_norm;
-                        for i in 0..loss_gradient.len() {
-                            loss_gradient[i] *= scale;
-                        }
-                    }
-                }
-                let _ = loss_gradient.iter().map(|x| x / self.batch_size as f64).collect::<Vec<f64>>();
-                
-                self.dense_backward(loss_gradient);
-        
-                self.cost /= self.batch_size as f64; // Compute average cost per sample
+    // Multi-label scores over a dataset, outputs go through the loss's predictions first (sigmoid for BCEWithLogits)
+    pub fn evaluate_multi_label(&mut self, dataset: impl Into<Dataset>, threshold: f64) -> MultiLabelMetrics {
+        let dataset = dataset.into();
+        let mut probabilities = Vec::with_capacity(dataset.len());
+        let mut targets = Vec::with_capacity(dataset.len());
+        for (input, target) in dataset.samples {
+            let output = self.predict(input);
+            probabilities.push(Tensor::new(self.loss_function.predictions(output.data()), output.shape()));
+            targets.push(target);
+        }
+        metrics::multi_label_metrics(&probabilities, &targets, threshold)
+    }
+
+    // Dense networks read every sample as a flat vector, conv networks as [channels, rows, cols]
+    fn prepare_input(&self, input: Tensor) -> Tensor {
+        match self.network_type {
+            NetworkType::FCN => input.flatten(),
+            NetworkType::CNN => match input.rank() {
+                2 => {
+                    let shape = [1, input.shape()[0], input.shape()[1]];
+                    input.reshape(&shape)
+                },
+                _ => input,
+            },
+        }
+    }
+
+    pub fn conv_train<I: Into<Tensor>, T: Into<Tensor>>(&mut self, data: Vec<(I, T)>, epochs: usize) {
+        if let Err(error) = self.fit(data, TrainConfig::new(epochs)) {
+            panic!("{}", error);
+        }
+    }
+
+    pub fn dense_train<D: Into<Tensor>>(&mut self, data: Vec<[D; 2]>, epochs: usize) {
+        if let Err(error) = self.fit(data, TrainConfig::new(epochs)) {
+            panic!("{}", error);
+        }
+    }
+
+    // One mini-batch update: the batch is forwarded as a single tensor and the per-sample loss
+    // gradients are averaged, so the accumulated parameter gradients are the batch mean.
+    // Returns each sample's cost
+    fn train_batch(&mut self, inputs: &[&Tensor], targets: &[&Tensor]) -> Vec<f64> {
+        let batch_size = inputs.len() as f64;
+        let outputs = self.forward(Tensor::stack(inputs));
+        let fused = self.fused();
+        let mut costs = Vec::with_capacity(inputs.len());
+        let mut loss_gradient = Vec::with_capacity(outputs.len());
+
+        for (n, target) in targets.iter().enumerate() { //each sample
+            let output = outputs.slice(n);
+            let target = target.data();
+            costs.push(self.loss_function.function(output, target));
+            if fused {
+                loss_gradient.extend(self.loss_function.fused_derivative(output, target));
+            } else {
+                loss_gradient.extend(self.loss_function.derivative(output, target));
             }
         }
-    
-        if self.print_progress {
-            println!("Training Complete");
+        let mut loss_gradient = Tensor::new(loss_gradient, outputs.shape());
+
+        // Clipping looks at the delta past the output activation, e.g. CEL's -t/p is huge for a
+        // confident wrong softmax while its delta isn't. Activation backward passes are linear in
+        // the errors, so scaling the errors scales that delta the same way
+        let delta = if fused {
+            loss_gradient.clone()
+        } else {
+            self.layers.last().map_or(loss_gradient.clone(), |layer| layer.activation_delta(&loss_gradient))
+        };
+        for n in 0..inputs.len() { //each sample
+            let l2_norm = delta.slice(n).iter().map(|x| x.powf(2.0)).sum::<f64>().sqrt();
+            let scale = if l2_norm > self.grad_threshold { self.grad_threshold / l2_norm } else { 1.0 };
+            for gradient in loss_gradient.slice_mut(n) {
+                *gradient *= scale / batch_size;
+            }
         }
+
+        self.backward(loss_gradient);
+        self.step();
+        costs
     }
 
     pub fn reset(&mut self) {
         self.cost = 0.0;
-        for i in 0..self.layers.len() {
-            self.layers[i].reset();
+        self.optimizer.reset();
+        for layer in self.layers.iter_mut() {
+            layer.reset();
         }
     }
 
     pub fn print_weights(&self) {
-        for i in 0..self.layers.len() {
-            println!("{:#?}", self.layers[i].get_dense_weights());
+        for layer in self.dense_layers() {
+            println!("{:#?}", layer.params.weights);
         }
     }
 
     pub fn print_biases(&self) {
-        for i in 0..self.layers.len() {
-            println!("{:#?}", self.layers[i].get_dense_biases());
+        for layer in self.dense_layers() {
+            println!("{:#?}", layer.params.biases);
         }
     }
 
-    pub fn get_weights(&self) -> (Vec<Vec<Vec<Vec<f64>>>>, Vec<Vec<Vec<f64>>>) {
-        let mut dense_weights = vec![];
-        let mut conv_weights = vec![];
-        for i in 0..self.layers.len() {
-            if self.layers[i].layer_type == LayerType::Dense {
-                dense_weights.push(self.layers[i].get_dense_weights());
-            } else {
-                conv_weights.push(self.layers[i].get_conv_weights());
-            }
-        }
+    fn dense_layers(&self) -> impl Iterator<Item = &Dense> {
+        self.layers.iter().filter_map(|layer| layer.downcast_ref::<Dense>())
+    }
+
+    fn conv_layers(&self) -> impl Iterator<Item = &Conv> {
+        self.layers.iter().filter_map(|layer| layer.downcast_ref::<Conv>())
+    }
+
+    pub fn get_weights(&self) -> (Vec<Tensor>, Vec<Tensor>) {
+        let conv_weights = self.conv_layers().map(|layer| layer.params.weights.clone()).collect();
+        let dense_weights = self.dense_layers().map(|layer| layer.params.weights.clone()).collect();
         (conv_weights, dense_weights)
     }
 
-    pub fn get_biases(&self) -> (Vec<f64>, Vec<Vec<f64>>) {
-        let mut dense_biases = vec![];
-        let mut conv_biases = vec![];
-        for i in 0..self.layers.len() {
-            if self.layers[i].layer_type == LayerType::Dense {
-                dense_biases.push(self.layers[i].get_dense_biases());
-            } else {
-                conv_biases.push(self.layers[i].get_conv_biases());
-            }
-        }
+    pub fn get_biases(&self) -> (Vec<Tensor>, Vec<Tensor>) {
+        let conv_biases = self.conv_layers().map(|layer| layer.params.biases.clone()).collect();
+        let dense_biases = self.dense_layers().map(|layer| layer.params.biases.clone()).collect();
         (conv_biases, dense_biases)
     }
 
-    pub fn get_conv_outputs(&self) -> Vec<Vec<Vec<Vec<f64>>>> {
+    pub fn get_conv_outputs(&self) -> Vec<Tensor> {
         let mut outputs = vec![];
-        for i in 0..self.layers.len() {
-            if self.layers[i].layer_type == LayerType::Dense {
+        for layer in &self.layers {
+            if let Some(conv) = layer.downcast_ref::<Conv>() {
+                outputs.push(conv.params.outputs.clone());
+            } else if let Some(pool) = layer.downcast_ref::<Pool>() {
+                outputs.push(pool.params.outputs.clone());
+            } else {
                 break;
             }
-            let output = self.layers[i].get_conv_outputs();
-            outputs.push(output)
         }
         outputs
     }
 
     pub fn get_nodes(&self) -> Vec<usize>{
-        let mut nodes = vec![];
-        for i in 0..self.layers.len() {
-            if self.layers[i].layer_type == LayerType::Convolutional {
-                continue;
-            }
-            nodes.push(self.layers[i].get_input_nodes());
+        let mut nodes: Vec<usize> = self.dense_layers().map(|layer| layer.params.nodes_in).collect();
+        if let Some(last) = self.dense_layers().last() {
+            nodes.push(last.params.nodes_out);
         }
-        nodes.push(self.layers[self.layers.len() - 1].get_nodes());
         nodes
     }
 
-    pub fn shuffle_vector(vec: &mut Vec<[Vec<f64>; 2]>) {
-        let mut rng = rand::thread_rng();
-        vec.shuffle(&mut rng);
+    // Per-sample output shape of the whole stack for a per-sample input shape
+    pub fn output_shape(&self, input_shape: &[usize]) -> Vec<usize> {
+        self.layers.iter().fold(input_shape.to_vec(), |shape, layer| layer.output_shape(&shape))
     }
 
-    pub fn shuffle_tensor(vec: &mut Vec<(Vec<Vec<Vec<f64>>>, Vec<f64>)>) {
-        let mut rng = rand::thread_rng();
-        vec.shuffle(&mut rng);
+    pub fn shuffle_vector(&mut self, vec: &mut [[Tensor; 2]]) {
+        vec.shuffle(&mut self.rng);
+    }
+
+    pub fn shuffle_tensor(&mut self, vec: &mut [(Tensor, Tensor)]) {
+        vec.shuffle(&mut self.rng);
     }
 
     pub fn save_model(&self, name: &str) {
@@ -379,4 +474,39 @@ impl Network {
         let model: Network = serde_json::from_str(&str).unwrap();
         model
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{activation::ActivationFunction, conv_params::PaddingType, layer::Layer, loss_function::{LossFunction, LossType}};
+
+    #[test]
+    fn fused_softmax_loss_needs_one_softmax_per_sample() {
+        let dense = Network::new(vec![Layer::dense([4, 3], ActivationFunction::SoftMax)], 0.1, 1, LossType::SoftmaxCrossEntropy);
+        assert!(dense.validate().is_ok() && dense.fused());
+        let conv = Network::new(vec![Layer::conv(1, 1, PaddingType::Valid, 1, ActivationFunction::SoftMax)], 0.1, 1, LossType::SoftmaxCrossEntropy);
+        assert!(conv.validate().is_err());
+        let conv = Network::new(vec![Layer::conv(1, 1, PaddingType::Valid, 1, ActivationFunction::SoftMax)], 0.1, 1, LossType::CEL);
+        assert!(conv.validate().is_ok() && !conv.fused());
+    }
+    #[test]
+    fn fit_rejects_weights_that_do_not_match_the_outputs() {
+        let mut loss = LossFunction::new(LossType::BCE);
+        loss.set_pos_weights(vec![1.0, 2.0]);
+        let mut network = Network::new(vec![Layer::dense([2, 3], ActivationFunction::Sigmoid)], 0.1, 1, loss);
+        let result = network.fit(vec![[vec![0.5, -0.5], vec![1.0, 0.0, 1.0]]], TrainConfig::new(1));
+        assert!(matches!(result, Err(NetworkError::ShapeMismatch(_))));
+    }
+    #[test]
+    fn set_seed_keeps_weights_and_reinit_draws_them_from_the_seed() {
+        let layers = || vec![Layer::dense([3, 2], ActivationFunction::Sigmoid)];
+        let mut network = Network::new(layers(), 0.1, 1, LossType::MSE);
+        let weights = network.get_weights();
+        network.set_seed(7);
+        assert_eq!(network.get_weights().1[0].data(), weights.1[0].data());
+        network.reinit(7);
+        let mut other = Network::new(layers(), 0.1, 1, LossType::MSE);
+        other.reinit(7);
+        assert_eq!(network.get_weights().1[0].data(), other.get_weights().1[0].data());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Serialize, Deserialize};
+pub use serde_json::Value;
+pub use crate::registry::Loader;
+
+use crate::{registry::{self, Registry}, tensor::Tensor};
+
+// Every parameter tensor in the network gets a stable id so optimizers can
+// keep per-parameter state (momentum buffers, moments...) between updates
+pub trait Optimizer {
+    fn update(&mut self, id: usize, params: &mut Tensor, grads: &Tensor);
+    fn learning_rate(&self) -> f64;
+    fn set_learning_rate(&mut self, learning_rate: f64);
+    fn reset(&mut self);
+    // The state carries the per-id buffers too, so a loaded model resumes training with its momentum
+    fn name(&self) -> &'static str;
+    fn state(&self) -> Value;
+}
+
+static OPTIMIZERS: Registry<dyn Optimizer> = Registry::new();
+
+// Custom optimizers must be registered under their name() before a model using them is loaded
+pub fn register_optimizer(name: &str, loader: Loader<dyn Optimizer>) {
+    OPTIMIZERS.register(name, loader);
+}
+
+fn load<T: Optimizer + for<'de> serde::Deserialize<'de> + 'static>(state: Value) -> Result<Box<dyn Optimizer>, serde_json::Error> {
+    Ok(Box::new(serde_json::from_value::<T>(state)?))
+}
+
+impl serde::Serialize for Box<dyn Optimizer> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        registry::serialize_named(self.name(), self.state(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Box<dyn Optimizer> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (name, state) = registry::deserialize_named(deserializer)?;
+        let optimizer = match name.as_str() {
+            "SGD" => load::<SGD>(state),
+            "Adam" => load::<Adam>(state),
+            "AdamW" => load::<AdamW>(state),
+            "RMSProp" => load::<RMSProp>(state),
+            _ => OPTIMIZERS.load(&name, state)
+                .ok_or_else(|| serde::de::Error::custom(format!("Unknown optimizer \"{}\", register it with register_optimizer", name)))?,
+        };
+        optimizer.map_err(serde::de::Error::custom)
+    }
+}
+
+fn state_buffer(states: &mut HashMap<usize, Vec<f64>>, id: usize, len: usize) -> &mut Vec<f64> {
+    let buffer = states.entry(id).or_insert_with(|| vec![0.0; len]);
+    if buffer.len() != len {
+        *buffer = vec![0.0; len];
+    }
+    buffer
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SGD {
+    pub learning_rate: f64,
+    pub momentum: f64,
+    pub nesterov: bool,
+    pub velocities: HashMap<usize, Vec<f64>>,
+}
+
+impl SGD {
+    pub fn new(learning_rate: f64) -> Self {
+        Self::momentum(learning_rate, 0.0)
+    }
+
+    pub fn momentum(learning_rate: f64, momentum: f64) -> Self {
+        SGD {
+            learning_rate,
+            momentum,
+            nesterov: false,
+            velocities: HashMap::new(),
+        }
+    }
+
+    pub fn nesterov(learning_rate: f64, momentum: f64) -> Self {
+        SGD {
+            nesterov: true,
+            ..Self::momentum(learning_rate, momentum)
+        }
+    }
+}
+
+impl Optimizer for SGD {
+    fn update(&mut self, id: usize, params: &mut Tensor, grads: &Tensor) {
+        if self.momentum == 0.0 {
+            for (param, grad) in params.data_mut().iter_mut().zip(grads.data()) {
+                *param -= self.learning_rate * grad;
+            }
+            return;
+        }
+        let velocity = state_buffer(&mut self.velocities, id, params.len());
+        for ((param, grad), v) in params.data_mut().iter_mut().zip(grads.data()).zip(velocity.iter_mut()) {
+            *v = self.momentum * *v + grad;
+            let step = if self.nesterov {
+                grad + self.momentum * *v
+            } else {
+                *v
+            };
+            *param -= self.learning_rate * step;
+        }
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn reset(&mut self) {
+        self.velocities.clear();
+    }
+
+    fn name(&self) -> &'static str {
+        "SGD"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Moments {
+    pub first: Vec<f64>,
+    pub second: Vec<f64>,
+    pub steps: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adam {
+    pub learning_rate: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub epsilon: f64,
+    pub moments: HashMap<usize, Moments>,
+}
+
+impl Adam {
+    pub fn new(learning_rate: f64) -> Self {
+        Self::with_betas(learning_rate, 0.9, 0.999)
+    }
+
+    pub fn with_betas(learning_rate: f64, beta1: f64, beta2: f64) -> Self {
+        Adam {
+            learning_rate,
+            beta1,
+            beta2,
+            epsilon: 1e-8,
+            moments: HashMap::new(),
+        }
+    }
+
+    fn step(&mut self, id: usize, params: &mut Tensor, grads: &Tensor) {
+        let moments = self.moments.entry(id).or_default();
+        if moments.first.len() != params.len() {
+            *moments = Moments {
+                first: vec![0.0; params.len()],
+                second: vec![0.0; params.len()],
+                steps: 0,
+            };
+        }
+        moments.steps += 1;
+        let first_correction = 1.0 - self.beta1.powi(moments.steps);
+        let second_correction = 1.0 - self.beta2.powi(moments.steps);
+
+        for (i, (param, grad)) in params.data_mut().iter_mut().zip(grads.data()).enumerate() {
+            moments.first[i] = self.beta1 * moments.first[i] + (1.0 - self.beta1) * grad;
+            moments.second[i] = self.beta2 * moments.second[i] + (1.0 - self.beta2) * grad * grad;
+            let first = moments.first[i] / first_correction;
+            let second = moments.second[i] / second_correction;
+            *param -= self.learning_rate * first / (second.sqrt() + self.epsilon);
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn update(&mut self, id: usize, params: &mut Tensor, grads: &Tensor) {
+        self.step(id, params, grads);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn reset(&mut self) {
+        self.moments.clear();
+    }
+
+    fn name(&self) -> &'static str {
+        "Adam"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+// Adam with weight decay applied to the parameters directly instead of through the gradients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdamW {
+    pub adam: Adam,
+    pub weight_decay: f64,
+}
+
+impl AdamW {
+    pub fn new(learning_rate: f64, weight_decay: f64) -> Self {
+        AdamW {
+            adam: Adam::new(learning_rate),
+            weight_decay,
+        }
+    }
+}
+
+impl Optimizer for AdamW {
+    fn update(&mut self, id: usize, params: &mut Tensor, grads: &Tensor) {
+        let decay = 1.0 - self.adam.learning_rate * self.weight_decay;
+        for param in params.data_mut() {
+            *param *= decay;
+        }
+        self.adam.step(id, params, grads);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.adam.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.adam.learning_rate = learning_rate;
+    }
+
+    fn reset(&mut self) {
+        self.adam.reset();
+    }
+
+    fn name(&self) -> &'static str {
+        "AdamW"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RMSProp {
+    pub learning_rate: f64,
+    pub decay: f64,
+    pub epsilon: f64,
+    pub squares: HashMap<usize, Vec<f64>>,
+}
+
+impl RMSProp {
+    pub fn new(learning_rate: f64) -> Self {
+        Self::with_decay(learning_rate, 0.9)
+    }
+
+    pub fn with_decay(learning_rate: f64, decay: f64) -> Self {
+        RMSProp {
+            learning_rate,
+            decay,
+            epsilon: 1e-8,
+            squares: HashMap::new(),
+        }
+    }
+}
+
+impl Optimizer for RMSProp {
+    fn update(&mut self, id: usize, params: &mut Tensor, grads: &Tensor) {
+        let squares = state_buffer(&mut self.squares, id, params.len());
+        for ((param, grad), square) in params.data_mut().iter_mut().zip(grads.data()).zip(squares.iter_mut()) {
+            *square = self.decay * *square + (1.0 - self.decay) * grad * grad;
+            *param -= self.learning_rate * grad / (square.sqrt() + self.epsilon);
+        }
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn reset(&mut self) {
+        self.squares.clear();
+    }
+
+    fn name(&self) -> &'static str {
+        "RMSProp"
+    }
+
+    fn state(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::RwLock;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Serialize, Deserialize};
+use serde_json::Value;
+
+pub type Loader<T> = fn(Value) -> Result<Box<T>, serde_json::Error>;
+
+// Name -> loader table used to rebuild boxed trait objects from saved models
+pub(crate) struct Registry<T: ?Sized> {
+    loaders: RwLock<Vec<(String, Loader<T>)>>,
+}
+
+impl<T: ?Sized> Registry<T> {
+    pub(crate) const fn new() -> Self {
+        Registry {
+            loaders: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn register(&self, name: &str, loader: Loader<T>) {
+        let mut loaders = self.loaders.write().unwrap();
+        loaders.retain(|(registered, _)| registered != name);
+        loaders.push((name.to_string(), loader));
+    }
+
+    pub(crate) fn load(&self, name: &str, state: Value) -> Option<Result<Box<T>, serde_json::Error>> {
+        let loaders = self.loaders.read().unwrap();
+        loaders.iter()
+            .find(|(registered, _)| registered == name)
+            .map(|(_, loader)| loader(state))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Named {
+    name: String,
+    state: Value,
+}
+
+pub(crate) fn serialize_named<S: Serializer>(name: &str, state: Value, serializer: S) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&Named { name: name.to_string(), state }, serializer)
+}
+
+pub(crate) fn deserialize_named<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(String, Value), D::Error> {
+    let named = <Named as serde::Deserialize>::deserialize(deserializer)?;
+    Ok((named.name, named.state))
+}
@@ -0,0 +1,323 @@
+use std::{borrow::Borrow, ops::{Index, IndexMut}};
+use serde_derive::{Serialize, Deserialize};
+
+// Row-major n-dimensional array backed by a single contiguous buffer
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Tensor {
+    data: Vec<f64>,
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+}
+
+// Borrowed window into a tensor's buffer, shape and strides can differ from the owner
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorView<'a> {
+    data: &'a [f64],
+    offset: usize,
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+}
+
+fn row_major_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+impl Tensor {
+    pub fn new(data: Vec<f64>, shape: &[usize]) -> Self {
+        assert_eq!(data.len(), shape.iter().product::<usize>(), "Data length does not match shape {:?}", shape);
+        Tensor {
+            data,
+            shape: shape.to_vec(),
+            strides: row_major_strides(shape),
+        }
+    }
+
+    pub fn zeros(shape: &[usize]) -> Self {
+        Self::filled(shape, 0.0)
+    }
+
+    pub fn filled(shape: &[usize], value: f64) -> Self {
+        Self::new(vec![value; shape.iter().product()], shape)
+    }
+
+    // Joins same-shaped tensors along a new leading axis
+    pub fn stack<T: Borrow<Tensor>>(items: &[T]) -> Self {
+        let item_shape = items.first().map_or(vec![], |item| item.borrow().shape.clone());
+        assert!(items.iter().all(|item| item.borrow().shape == item_shape), "Stacked tensors must all have the same shape");
+        let mut shape = vec![items.len()];
+        shape.extend(item_shape);
+        let data = items.iter().flat_map(|item| item.borrow().data.iter().copied()).collect();
+        Tensor::new(data, &shape)
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn data(&self) -> &[f64] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f64] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f64> {
+        self.data
+    }
+
+    // Reuses the buffer, only the shape and strides change
+    pub fn reshape(mut self, shape: &[usize]) -> Self {
+        assert_eq!(self.data.len(), shape.iter().product::<usize>(), "Cannot reshape {:?} into {:?}", self.shape, shape);
+        self.shape = shape.to_vec();
+        self.strides = row_major_strides(shape);
+        self
+    }
+
+    pub fn flatten(self) -> Self {
+        let len = self.data.len();
+        self.reshape(&[len])
+    }
+
+    pub fn view(&self) -> TensorView<'_> {
+        TensorView {
+            data: &self.data,
+            offset: 0,
+            shape: self.shape.clone(),
+            strides: self.strides.clone(),
+        }
+    }
+
+    // Contiguous block of index i along the first axis (a channel, a row...)
+    pub fn slice(&self, i: usize) -> &[f64] {
+        let size = self.strides[0];
+        &self.data[i * size..(i + 1) * size]
+    }
+
+    pub fn slice_mut(&mut self, i: usize) -> &mut [f64] {
+        let size = self.strides[0];
+        &mut self.data[i * size..(i + 1) * size]
+    }
+
+    pub fn offset(&self, index: &[usize]) -> usize {
+        debug_assert_eq!(index.len(), self.shape.len(), "Index rank does not match tensor rank");
+        index.iter().zip(&self.strides).map(|(i, s)| i * s).sum()
+    }
+
+    pub fn get(&self, index: &[usize]) -> f64 {
+        self.data[self.offset(index)]
+    }
+
+    pub fn fill(&mut self, value: f64) {
+        self.data.iter_mut().for_each(|x| *x = value);
+    }
+
+    pub fn to_vec2(&self) -> Vec<Vec<f64>> {
+        assert_eq!(self.rank(), 2, "Expected a rank 2 tensor");
+        self.data.chunks(self.strides[0].max(1)).map(|row| row.to_vec()).collect()
+    }
+
+    pub fn to_vec3(&self) -> Vec<Vec<Vec<f64>>> {
+        assert_eq!(self.rank(), 3, "Expected a rank 3 tensor");
+        let cols = self.shape[2].max(1);
+        self.data.chunks(self.strides[0].max(1))
+            .map(|mat| mat.chunks(cols).map(|row| row.to_vec()).collect())
+            .collect()
+    }
+}
+
+impl<'a> TensorView<'a> {
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.strides == row_major_strides(&self.shape)
+    }
+
+    pub fn get(&self, index: &[usize]) -> f64 {
+        debug_assert_eq!(index.len(), self.shape.len(), "Index rank does not match view rank");
+        let offset: usize = index.iter().zip(&self.strides).map(|(i, s)| i * s).sum();
+        self.data[self.offset + offset]
+    }
+
+    // Drops the first axis by fixing it to i
+    pub fn at(&self, i: usize) -> TensorView<'a> {
+        assert!(i < self.shape[0], "Index {} out of bounds for axis of length {}", i, self.shape[0]);
+        TensorView {
+            data: self.data,
+            offset: self.offset + i * self.strides[0],
+            shape: self.shape[1..].to_vec(),
+            strides: self.strides[1..].to_vec(),
+        }
+    }
+
+    // Reverses the axes without touching the buffer
+    pub fn transpose(&self) -> TensorView<'a> {
+        let mut view = self.clone();
+        view.shape.reverse();
+        view.strides.reverse();
+        view
+    }
+
+    pub fn reshape(&self, shape: &[usize]) -> TensorView<'a> {
+        assert!(self.is_contiguous(), "Only contiguous views can be reshaped");
+        assert_eq!(self.len(), shape.iter().product::<usize>(), "Cannot reshape {:?} into {:?}", self.shape, shape);
+        TensorView {
+            data: self.data,
+            offset: self.offset,
+            shape: shape.to_vec(),
+            strides: row_major_strides(shape),
+        }
+    }
+
+    pub fn as_slice(&self) -> Option<&'a [f64]> {
+        if self.is_contiguous() {
+            Some(&self.data[self.offset..self.offset + self.len()])
+        } else {
+            None
+        }
+    }
+
+    pub fn to_tensor(&self) -> Tensor {
+        if let Some(slice) = self.as_slice() {
+            return Tensor::new(slice.to_vec(), &self.shape);
+        }
+        let mut data = Vec::with_capacity(self.len());
+        let mut index = vec![0; self.shape.len()];
+        for _ in 0..self.len() {
+            data.push(self.get(&index));
+            for axis in (0..index.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < self.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Tensor::new(data, &self.shape)
+    }
+}
+
+impl Index<usize> for Tensor {
+    type Output = f64;
+
+    fn index(&self, index: usize) -> &f64 {
+        &self.data[index]
+    }
+}
+
+impl IndexMut<usize> for Tensor {
+    fn index_mut(&mut self, index: usize) -> &mut f64 {
+        &mut self.data[index]
+    }
+}
+
+impl<const N: usize> Index<[usize; N]> for Tensor {
+    type Output = f64;
+
+    fn index(&self, index: [usize; N]) -> &f64 {
+        &self.data[self.offset(&index)]
+    }
+}
+
+impl<const N: usize> IndexMut<[usize; N]> for Tensor {
+    fn index_mut(&mut self, index: [usize; N]) -> &mut f64 {
+        let offset = self.offset(&index);
+        &mut self.data[offset]
+    }
+}
+
+impl From<Vec<f64>> for Tensor {
+    fn from(data: Vec<f64>) -> Self {
+        let len = data.len();
+        Tensor::new(data, &[len])
+    }
+}
+
+impl From<&[f64]> for Tensor {
+    fn from(data: &[f64]) -> Self {
+        Tensor::from(data.to_vec())
+    }
+}
+
+impl From<Vec<Vec<f64>>> for Tensor {
+    fn from(mat: Vec<Vec<f64>>) -> Self {
+        let rows = mat.len();
+        let cols = mat.first().map_or(0, |row| row.len());
+        assert!(mat.iter().all(|row| row.len() == cols), "Rows must all have the same length");
+        Tensor::new(mat.into_iter().flatten().collect(), &[rows, cols])
+    }
+}
+
+impl From<Vec<Vec<Vec<f64>>>> for Tensor {
+    fn from(img: Vec<Vec<Vec<f64>>>) -> Self {
+        let channels = img.len();
+        let mats: Vec<Tensor> = img.into_iter().map(Tensor::from).collect();
+        let mat_shape = mats.first().map_or(vec![0, 0], |mat| mat.shape.clone());
+        assert!(mats.iter().all(|mat| mat.shape == mat_shape), "Channels must all have the same dimensions");
+        let data = mats.into_iter().flat_map(|mat| mat.data).collect();
+        Tensor::new(data, &[channels, mat_shape[0], mat_shape[1]])
+    }
+}
+
+impl From<&Vec<Vec<Vec<f64>>>> for Tensor {
+    fn from(img: &Vec<Vec<Vec<f64>>>) -> Self {
+        Tensor::from(img.clone())
+    }
+}
+
+impl From<Vec<Vec<Vec<Vec<f64>>>>> for Tensor {
+    fn from(batch: Vec<Vec<Vec<Vec<f64>>>>) -> Self {
+        let items = batch.len();
+        let imgs: Vec<Tensor> = batch.into_iter().map(Tensor::from).collect();
+        let img_shape = imgs.first().map_or(vec![0, 0, 0], |img| img.shape.clone());
+        assert!(imgs.iter().all(|img| img.shape == img_shape), "Items must all have the same dimensions");
+        let data = imgs.into_iter().flat_map(|img| img.data).collect();
+        Tensor::new(data, &[items, img_shape[0], img_shape[1], img_shape[2]])
+    }
+}
+
+impl From<TensorView<'_>> for Tensor {
+    fn from(view: TensorView<'_>) -> Self {
+        view.to_tensor()
+    }
+}
+
+impl From<Tensor> for Vec<f64> {
+    fn from(tensor: Tensor) -> Self {
+        tensor.data
+    }
+}
@@ -0,0 +1,46 @@
+use sprout::{activation::ActivationFunction, layer::{Conv, Dense}, network::Network, tensor::Tensor};
+
+// Saved by the baseline tree (Layer::dense / Layer::conv and save_model) with the outputs it predicted
+const DENSE_MODEL: &str = r#"{"layers":[{"activation":{"function":"ReLU"},"layer_type":"Dense","conv_params":null,"dense_params":{"nodes_in":2,"nodes_out":3,"outputs":[],"inputs":[],"weights":[[1.7265748709096855,-1.3477138541729687,-0.6529945876630403],[1.1485074028544826,0.5202564944291188,-0.112887037928463]],"biases":[0.2,-0.1,0.3]}},{"activation":{"function":"Sigmoid"},"layer_type":"Dense","conv_params":null,"dense_params":{"nodes_in":3,"nodes_out":1,"outputs":[],"inputs":[],"weights":[[-0.2938751053429754],[0.9234839840072953],[0.5243105735752595]],"biases":[0.0]}}],"learning_rate":0.1,"batch_size":1,"cost":0.0,"print_progress":false,"network_type":"FCN","loss_function":{"loss_type":"MSE"},"grad_threshold":0.2}"#;
+const CONV_MODEL: &str = r#"{"layers":[{"activation":{"function":"ReLU"},"layer_type":"Convolutional","conv_params":{"kernel":2,"padding_type":"Same","padding":0,"stride":1,"data":[[[0.1,-0.2,0.3],[0.4,0.5,-0.6],[0.7,0.8,0.9]],[[-0.3,0.2,0.1],[0.6,-0.5,0.4],[0.9,0.1,-0.7]]],"weights":[[[0.4762497052447112,0.06144043947178379],[0.2680174338860122,-0.17656747783327842]],[[-0.14269483819176676,0.29367354018245706],[0.07879671841304625,0.13665016263280538]]],"bias":0.05,"outputs":[[[0.05426011726788005,0.163131394435566],[0.2675783232873621,0.2567638059981445]],[[0.0804961092254465,0.01609009222649138],[-0.0014787161017126643,0.10104139316720706]]],"inputs":[[[0.1,-0.2,0.3],[0.4,0.5,-0.6],[0.7,0.8,0.9]],[[-0.3,0.2,0.1],[0.6,-0.5,0.4],[0.9,0.1,-0.7]]]},"dense_params":null},{"activation":{"function":"ReLU"},"layer_type":"Pooling","conv_params":{"kernel":2,"padding_type":"Valid","padding":0,"stride":2,"data":[[[0.05426011726788005,0.163131394435566],[0.2675783232873621,0.2567638059981445]],[[0.0804961092254465,0.01609009222649138],[-0.0014787161017126643,0.10104139316720706]]],"weights":[[[-0.1818082650587165,0.10948633886641534],[0.2863411421548254,0.18726495786199826]],[[-0.04317592884445398,0.1211405675818138],[-0.2829385572696297,-0.1163820221640206]]],"bias":0.0,"outputs":[[[0.2675783232873621]],[[0.10104139316720706]]],"inputs":[[[0.05426011726788005,0.163131394435566],[0.2675783232873621,0.2567638059981445]],[[0.0804961092254465,0.01609009222649138],[-0.0014787161017126643,0.10104139316720706]]]},"dense_params":null},{"activation":{"function":"SoftMax"},"layer_type":"Dense","conv_params":null,"dense_params":{"nodes_in":2,"nodes_out":2,"outputs":[0.53211493172017,0.46788506827983006],"inputs":[0.2675783232873621,0.10104139316720706],"weights":[[0.6489511843832233,-0.15704901629256934],[-0.1760628385795398,0.6852808307841353]],"biases":[0.1,-0.1]}}],"learning_rate":0.1,"batch_size":1,"cost":0.0,"print_progress":false,"network_type":"CNN","loss_function":{"loss_type":"CEL"},"grad_threshold":0.2}"#;
+
+fn load(name: &str, json: &str) -> Network {
+    let path = std::env::temp_dir().join(format!("sprout_baseline_{}_{}", name, std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(format!("{}.json", path), json).unwrap();
+    let model = Network::from_load(path);
+    std::fs::remove_file(format!("{}.json", path)).unwrap();
+    model
+}
+
+fn assert_close(actual: &Tensor, expected: &[f64]) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.data().iter().zip(expected) {
+        assert!((a - e).abs() < 1e-12, "{:?} != {:?}", actual.data(), expected);
+    }
+}
+
+#[test]
+fn loads_baseline_dense_model() {
+    let mut model = load("dense", DENSE_MODEL);
+    let hidden = model.layers[0].downcast_ref::<Dense>().unwrap();
+    assert_eq!(hidden.activation.function, ActivationFunction::LeakyReLU(0.01));
+
+    assert_close(&model.predict(vec![0.5, -0.5]), &[0.465663528595241]);
+    assert_close(&model.predict(vec![1.0, 1.0]), &[0.2860395023626722]);
+    assert_close(&model.predict(vec![-1.0, 0.5]), &[0.8659179080670802]);
+}
+
+#[test]
+fn loads_baseline_conv_model() {
+    let mut model = load("conv", CONV_MODEL);
+    let conv = model.layers[0].downcast_ref::<Conv>().unwrap();
+    assert_eq!(conv.activation.function, ActivationFunction::LeakyReLU(0.01));
+    assert_eq!(conv.params.weights.shape(), &[2, 2, 2, 2]);
+
+    let img = vec![
+        vec![vec![0.1, -0.2, 0.3], vec![0.4, 0.5, -0.6], vec![0.7, 0.8, 0.9]],
+        vec![vec![-0.3, 0.2, 0.1], vec![0.6, -0.5, 0.4], vec![0.9, 0.1, -0.7]],
+    ];
+    assert_close(&model.predict(img), &[0.5787314713166963, 0.4212685286833036]);
+}